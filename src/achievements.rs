@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+
+use crate::quoridor::{
+    cpu::{CpuDifficulty, CPU},
+    MatchResult, Outcome, QuoridorMatch, WinReason,
+};
+
+const WIN_STREAK_TARGET: usize = 10;
+/// Wall badges need a real game, not a concede on the first move.
+const WALL_BADGE_MIN_TURNS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Achievement {
+    FirstWin,
+    CpuSlayer,
+    WonWithoutWalls,
+    OpponentWallsUntouched,
+    WinStreak,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct AchievementRecord {
+    unlocked: Vec<Achievement>,
+    win_streak: usize,
+}
+
+pub struct Achievements {
+    db: sled::Db,
+}
+
+impl Default for Achievements {
+    fn default() -> Self {
+        Self {
+            db: sled::open("achievements").expect("Unable to start DB!"),
+        }
+    }
+}
+
+impl Achievements {
+    pub fn get_by_email(&self, email: &str) -> Vec<Achievement> {
+        self.get_record(email).unlocked
+    }
+
+    /// Evaluates the rules against a finished match and returns only the newly unlocked badges.
+    /// Only registered players are passed in, guests keep no records.
+    pub fn process_game(&self, email: &str, snapshot: &QuoridorMatch) -> Vec<Achievement> {
+        let outcome = match snapshot.outcome_for(email) {
+            Some(outcome) => outcome,
+            None => return Vec::new(),
+        };
+        let mut record = self.get_record(email);
+        let earned = evaluate(email, outcome, snapshot, &mut record.win_streak);
+        let fresh: Vec<Achievement> = earned
+            .into_iter()
+            .filter(|achievement| !record.unlocked.contains(achievement))
+            .collect();
+        record.unlocked.extend(fresh.iter().copied());
+        if let Ok(value) = to_string(&record) {
+            let _ = self.db.insert(email, value.as_bytes());
+        }
+        fresh
+    }
+
+    fn get_record(&self, email: &str) -> AchievementRecord {
+        self.db
+            .get(email)
+            .ok()
+            .flatten()
+            .and_then(|record| {
+                std::str::from_utf8(&record)
+                    .ok()
+                    .and_then(|data| from_str::<AchievementRecord>(data).ok())
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Draws and losses both break the win streak, CPU games with a takeback count for nothing.
+fn evaluate(player: &str, outcome: Outcome, snapshot: &QuoridorMatch, win_streak: &mut usize) -> Vec<Achievement> {
+    let mut earned = Vec::new();
+    if snapshot.contains_player(CPU) {
+        if outcome == Outcome::Win && snapshot.settings.difficulty == CpuDifficulty::Hard && !snapshot.had_takeback() {
+            earned.push(Achievement::CpuSlayer);
+        }
+        return earned;
+    }
+    if outcome != Outcome::Win {
+        *win_streak = 0;
+        return earned;
+    }
+    *win_streak += 1;
+    earned.push(Achievement::FirstWin);
+    let starting_walls = snapshot.settings.walls_per_player();
+    let reached_goal = matches!(
+        snapshot.result,
+        Some(MatchResult::Win {
+            reason: WinReason::ReachedGoal,
+            ..
+        })
+    );
+    if reached_goal && starting_walls > 0 && snapshot.turns() >= WALL_BADGE_MIN_TURNS {
+        if snapshot.free_walls(player) == starting_walls {
+            earned.push(Achievement::WonWithoutWalls);
+        }
+        if snapshot.free_walls(snapshot.opponent_of(player)) == starting_walls {
+            earned.push(Achievement::OpponentWallsUntouched);
+        }
+    }
+    if *win_streak >= WIN_STREAK_TARGET {
+        earned.push(Achievement::WinStreak);
+    }
+    earned
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::PlayerMove;
+    use crate::quoridor::MatchSettings;
+
+    #[test]
+    fn wall_badges_need_a_played_game() {
+        let players = ["pl1".to_owned(), "pl2".to_owned()];
+        let mut conceded = QuoridorMatch::new(&players, MatchSettings::default());
+        conceded.make_move(PlayerMove::Concede, "pl2");
+        let earned = evaluate("pl1", Outcome::Win, &conceded, &mut 0);
+        assert_eq!(earned, vec![Achievement::FirstWin]);
+    }
+
+    #[test]
+    fn only_the_hard_cpu_counts() {
+        for (difficulty, expected) in [
+            (CpuDifficulty::Easy, vec![]),
+            (CpuDifficulty::Hard, vec![Achievement::CpuSlayer]),
+        ] {
+            let settings = MatchSettings {
+                difficulty,
+                ..MatchSettings::default()
+            };
+            let mut game = QuoridorMatch::new(&["pl1".to_owned()], settings);
+            game.make_move(PlayerMove::Concede, CPU);
+            assert_eq!(evaluate("pl1", Outcome::Win, &game, &mut 0), expected);
+        }
+    }
+}
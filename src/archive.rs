@@ -0,0 +1,32 @@
+use serde_json::{from_slice, to_vec};
+
+use crate::quoridor::QuoridorMatch;
+
+/// Finished matches, kept after they leave memory so they can be replayed.
+pub struct MatchArchive {
+    db: sled::Db,
+}
+
+impl Default for MatchArchive {
+    fn default() -> Self {
+        Self {
+            db: sled::open("archive").expect("Unable to start DB!"),
+        }
+    }
+}
+
+impl MatchArchive {
+    pub fn save(&self, id: &str, game: &QuoridorMatch) {
+        if let Ok(value) = to_vec(game) {
+            let _ = self.db.insert(id, value);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<QuoridorMatch> {
+        self.db
+            .get(id)
+            .ok()
+            .flatten()
+            .and_then(|record| from_slice::<QuoridorMatch>(&record).ok())
+    }
+}
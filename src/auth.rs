@@ -4,42 +4,106 @@ use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string};
 
 use crate::errors::StateError;
-use crate::messages::UserContext;
+use crate::messages::{PrivacySettings, UserContext};
+use crate::state::generate_id;
+
+const USER_ID_LEN: usize = 12;
 
 #[derive(Serialize, Deserialize)]
 struct UserData {
     username: String,
     password_hash: String,
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    joined: i64,
+    #[serde(default)]
+    privacy: PrivacySettings,
+}
+
+pub struct PublicUser {
+    pub email: String,
+    pub username: String,
+    pub joined: i64,
+    pub privacy: PrivacySettings,
 }
 
 pub struct Users {
     db: sled::Db,
+    ids: sled::Tree,
     email_check: Regex,
 }
 
 impl Default for Users {
     fn default() -> Self {
-        Self {
-            db: sled::open("users").expect("Unable to start DB!"),
+        let db = sled::open("users").expect("Unable to start DB!");
+        let users = Self {
+            ids: db.open_tree("ids").expect("Unable to start DB!"),
+            db,
             email_check: Regex::new(
                 r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@([a-z0-9]+([\-\.]{1}[a-z0-9]+)*\.[a-z]{2,6})",
             )
             .expect("Regex creation should not fail!"),
-        }
+        };
+        users.backfill_ids();
+        users
     }
 }
 
 impl Users {
     pub fn get(&self, email: &str, password: &str, token: String) -> Result<UserContext, StateError> {
-        let user_data = self.is_authenticated(email, password)?;
+        let mut user_data = self.is_authenticated(email, password)?;
+        if user_data.id.is_empty() {
+            user_data.id = self.new_user_id();
+            self.save(email, &user_data)?;
+        }
         Ok(UserContext {
             email: email.to_owned(),
             auth_token: token,
             username: user_data.username,
+            user_id: Some(user_data.id),
             active_match: None,
+            active_matches: Vec::new(),
         })
     }
 
+    pub fn get_public(&self, user_id: &str) -> Result<PublicUser, StateError> {
+        let email = self
+            .ids
+            .get(user_id)
+            .map_err(|_| StateError::ServerError)?
+            .ok_or(StateError::NotFound)?;
+        let email = std::str::from_utf8(&email).map_err(|_| StateError::ServerError)?;
+        let user = self.get_data(email)?;
+        Ok(PublicUser {
+            email: email.to_owned(),
+            username: user.username,
+            joined: user.joined,
+            privacy: user.privacy,
+        })
+    }
+
+    pub fn get_public_id(&self, email: &str) -> Option<String> {
+        self.get_data(email)
+            .ok()
+            .map(|user| user.id)
+            .filter(|id| !id.is_empty())
+    }
+
+    pub fn get_username(&self, email: &str) -> Option<String> {
+        self.get_data(email).ok().map(|user| user.username)
+    }
+
+    pub fn get_privacy(&self, email: &str) -> PrivacySettings {
+        self.get_data(email).map(|user| user.privacy).unwrap_or_default()
+    }
+
+    pub fn set_privacy(&self, email: &str, privacy: PrivacySettings) -> Result<(), StateError> {
+        let mut user = self.get_data(email)?;
+        user.privacy = privacy;
+        self.save(email, &user)
+    }
+
     pub fn new_user(
         &self,
         username: String,
@@ -61,13 +125,15 @@ impl Users {
         let user_payload = UserData {
             username: username.to_owned(),
             password_hash: hash(password, DEFAULT_COST).map_err(|_| StateError::ServerError)?,
+            id: self.new_user_id(),
+            joined: chrono::Utc::now().timestamp(),
+            privacy: PrivacySettings::default(),
         };
-        let user_json = to_string(&user_payload).map_err(|_| StateError::ServerError)?;
-        self.db
-            .insert(&email, user_json.as_bytes())
-            .map_err(|_| StateError::ServerError)?;
+        self.save(&email, &user_payload)?;
         Ok(UserContext {
             active_match: None,
+            active_matches: Vec::new(),
+            user_id: Some(user_payload.id),
             auth_token: token,
             email,
             username,
@@ -75,16 +141,59 @@ impl Users {
     }
 
     fn is_authenticated(&self, email: &str, password: &str) -> Result<UserData, StateError> {
+        let user = self.get_data(email)?;
+        if verify(password, &user.password_hash).map_err(|_| StateError::ServerError)? {
+            return Ok(user);
+        }
+        Err(StateError::Unauthorized)
+    }
+
+    fn get_data(&self, email: &str) -> Result<UserData, StateError> {
         let record = self
             .db
             .get(email)
             .map_err(|_| StateError::ServerError)?
             .ok_or(StateError::NotFound)?;
         let serialized_user = std::str::from_utf8(&record).map_err(|_| StateError::ServerError)?;
-        let user = from_str::<UserData>(serialized_user).map_err(|_| StateError::ServerError)?;
-        if verify(password, &user.password_hash).map_err(|_| StateError::ServerError)? {
-            return Ok(user);
+        from_str::<UserData>(serialized_user).map_err(|_| StateError::ServerError)
+    }
+
+    fn save(&self, email: &str, user: &UserData) -> Result<(), StateError> {
+        let user_json = to_string(user).map_err(|_| StateError::ServerError)?;
+        self.db
+            .insert(email, user_json.as_bytes())
+            .map_err(|_| StateError::ServerError)?;
+        if !user.id.is_empty() {
+            self.ids
+                .insert(&user.id, email.as_bytes())
+                .map_err(|_| StateError::ServerError)?;
         }
-        Err(StateError::Unauthorized)
+        Ok(())
+    }
+
+    /// Accounts from before public ids existed get one at startup, not only on their next login.
+    fn backfill_ids(&self) {
+        let missing: Vec<(String, UserData)> = self
+            .db
+            .iter()
+            .flatten()
+            .filter_map(|(email, user)| {
+                let email = std::str::from_utf8(&email).ok()?.to_owned();
+                let user = from_str::<UserData>(std::str::from_utf8(&user).ok()?).ok()?;
+                user.id.is_empty().then_some((email, user))
+            })
+            .collect();
+        for (email, mut user) in missing {
+            user.id = self.new_user_id();
+            let _ = self.save(&email, &user);
+        }
+    }
+
+    fn new_user_id(&self) -> String {
+        let mut id = generate_id(USER_ID_LEN);
+        while self.ids.contains_key(&id).unwrap_or(false) {
+            id = generate_id(USER_ID_LEN);
+        }
+        id
     }
 }
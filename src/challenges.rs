@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::StateError;
+use crate::quoridor::MatchSettings;
+use crate::state::generate_id;
+
+const CODE_LEN: usize = 24;
+const CHALLENGE_TTL: i64 = 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct ChallengeCreate {
+    /// Public id of the invited user.
+    pub invitee: Option<String>,
+    #[serde(default)]
+    pub settings: MatchSettings,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    pub code: String,
+    /// Emails stay on the server, clients see the public ids.
+    #[serde(skip)]
+    pub host: String,
+    #[serde(skip)]
+    pub invitee: Option<String>,
+    /// Guests have no public id.
+    #[serde(rename = "host")]
+    pub host_id: Option<String>,
+    #[serde(rename = "invitee")]
+    pub invitee_id: Option<String>,
+    pub settings: MatchSettings,
+    pub created: i64,
+    pub match_id: Option<String>,
+}
+
+impl Challenge {
+    pub fn is_open(&self) -> bool {
+        self.match_id.is_none()
+    }
+
+    /// Open challenges without an invitee can be seen and accepted by whoever holds the code.
+    pub fn is_visible_to(&self, player: &str) -> bool {
+        self.host == player
+            || self
+                .invitee
+                .as_ref()
+                .map_or(self.is_open(), |invitee| invitee == player)
+    }
+}
+
+#[derive(Default)]
+pub struct Challenges {
+    challenges: HashMap<String, Challenge>,
+}
+
+impl Challenges {
+    /// Host and invitee come as email and public id.
+    pub fn create(
+        &mut self,
+        (host, host_id): (&str, Option<String>),
+        invitee: Option<(String, String)>,
+        settings: MatchSettings,
+    ) -> Result<Challenge, StateError> {
+        let (invitee, invitee_id) = invitee.unzip();
+        if invitee.as_deref() == Some(host) {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        let mut code = generate_id(CODE_LEN);
+        while self.challenges.contains_key(&code) {
+            code = generate_id(CODE_LEN);
+        }
+        let challenge = Challenge {
+            code: code.to_owned(),
+            host: host.to_owned(),
+            invitee,
+            host_id,
+            invitee_id,
+            settings,
+            created: chrono::Utc::now().timestamp(),
+            match_id: None,
+        };
+        self.challenges.insert(code, challenge.clone());
+        Ok(challenge)
+    }
+
+    pub fn get(&self, code: &str, player: &str) -> Result<Challenge, StateError> {
+        self.challenges
+            .get(code)
+            .filter(|challenge| challenge.is_visible_to(player))
+            .cloned()
+            .ok_or(StateError::NotFound)
+    }
+
+    /// Returns the challenge if `player` is allowed to accept it, the caller has to mark it with `set_match`.
+    pub fn acceptable(&self, code: &str, player: &str) -> Result<Challenge, StateError> {
+        let challenge = self.get(code, player)?;
+        if !challenge.is_open() {
+            return Err(StateError::AlreadyTaken);
+        }
+        if challenge.host == player {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        Ok(challenge)
+    }
+
+    pub fn set_match(&mut self, code: &str, match_id: String) {
+        if let Some(challenge) = self.challenges.get_mut(code) {
+            challenge.match_id = Some(match_id);
+        }
+    }
+
+    pub fn cancel(&mut self, code: &str, player: &str) -> Result<(), StateError> {
+        match self.challenges.get(code) {
+            Some(challenge) if challenge.host == player && challenge.is_open() => {
+                self.challenges.remove(code);
+                Ok(())
+            }
+            Some(challenge) if challenge.host == player => Err(StateError::AlreadyTaken),
+            _ => Err(StateError::NotFound),
+        }
+    }
+
+    /// Challenges hosted by the player and open challenges sent directly to them.
+    pub fn pending_for(&self, player: &str) -> Vec<Challenge> {
+        self.challenges
+            .values()
+            .filter(|challenge| {
+                challenge.host == player || challenge.is_open() && challenge.invitee.as_deref() == Some(player)
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn clean_up(&mut self) {
+        let expired = chrono::Utc::now().timestamp() - CHALLENGE_TTL;
+        self.challenges.retain(|_, challenge| challenge.created > expired);
+    }
+}
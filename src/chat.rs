@@ -0,0 +1,329 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::messages::{ChatFrame, ChatMessage};
+use crate::state::channel_capacity;
+
+pub const LOBBY_CHANNEL: &str = "lobby";
+pub const REPLAY_LEN: usize = 50;
+const NONCE_WINDOW: usize = 50;
+const PAGE_LEN: usize = 50;
+const MAX_MESSAGES_PER_CHANNEL: usize = 1000;
+const RETENTION: i64 = 30 * 24 * 60 * 60;
+const CHAT_CAPACITY_VAR: &str = "CHAT_CHANNEL_CAPACITY";
+const DEFAULT_CHAT_CAPACITY: usize = 50;
+const DIRTY: u8 = 1;
+const CLEAN: u8 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ChatAccess {
+    Public,
+    /// Players write, everyone else can only read along.
+    Match {
+        players: Vec<String>,
+    },
+    Private {
+        members: Vec<String>,
+    },
+}
+
+impl ChatAccess {
+    pub fn can_read(&self, user: &str) -> bool {
+        match self {
+            ChatAccess::Private { members } => members.iter().any(|member| member == user),
+            _ => true,
+        }
+    }
+
+    pub fn can_write(&self, user: &str) -> bool {
+        match self {
+            ChatAccess::Public => true,
+            ChatAccess::Match { players: members } | ChatAccess::Private { members } => {
+                members.iter().any(|member| member == user)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ChatChannel {
+    pub sender: broadcast::Sender<ChatFrame>,
+    pub access: ChatAccess,
+}
+
+impl ChatChannel {
+    pub fn new(access: ChatAccess) -> Self {
+        Self {
+            sender: broadcast::channel::<ChatFrame>(channel_capacity(CHAT_CAPACITY_VAR, DEFAULT_CHAT_CAPACITY)).0,
+            access,
+        }
+    }
+}
+
+/// Waits for the next frame, `None` once the channel is gone.
+/// A lagging subscriber gets a marker with the number of skipped frames and carries on with the oldest kept one.
+pub async fn next_frame(receiver: &mut broadcast::Receiver<ChatFrame>) -> Option<ChatFrame> {
+    match receiver.recv().await {
+        Ok(frame) => Some(frame),
+        Err(RecvError::Lagged(count)) => Some(ChatFrame::Missed { count }),
+        Err(RecvError::Closed) => None,
+    }
+}
+
+/// Spectators of a match talk in their own channel next to the players' one.
+pub fn spectator_channel(match_id: &str) -> String {
+    format!("{match_id}-spectators")
+}
+
+/// Chat messages per channel, keyed by `channel/id` with monotonic ids so a prefix scan is chronological.
+/// Channel access rules are kept as well, so history stays protected after the channel is dropped.
+/// A rematch aliases its channels to the ones of the first match, so the conversation carries on.
+/// Retention works on keys only: `checkpoints` maps each minute to the last id stored in it and
+/// `channels` flags the channels that got messages since the last clean up.
+pub struct ChatHistory {
+    db: sled::Db,
+    access: sled::Tree,
+    aliases: sled::Tree,
+    channels: sled::Tree,
+    checkpoints: sled::Tree,
+}
+
+impl Default for ChatHistory {
+    fn default() -> Self {
+        Self::open(sled::open("chat").expect("Unable to start DB!"))
+    }
+}
+
+impl ChatHistory {
+    fn open(db: sled::Db) -> Self {
+        let history = Self {
+            access: db.open_tree("access").expect("Unable to start DB!"),
+            aliases: db.open_tree("aliases").expect("Unable to start DB!"),
+            channels: db.open_tree("channels").expect("Unable to start DB!"),
+            checkpoints: db.open_tree("checkpoints").expect("Unable to start DB!"),
+            db,
+        };
+        history.backfill_checkpoints();
+        history
+    }
+
+    /// Assigns the message id, ids are unique across channels and survive restarts.
+    pub fn push(&self, channel: &str, mut message: ChatMessage) -> ChatMessage {
+        let channel = &self.resolve(channel);
+        message.id = self.db.generate_id().unwrap_or_default();
+        if let Ok(value) = to_vec(&message) {
+            let _ = self.db.insert(key(channel, message.id), value);
+            self.mark(channel, chrono::Utc::now().timestamp(), message.id);
+        }
+        message
+    }
+
+    fn mark(&self, channel: &str, timestamp: i64, id: u64) {
+        let _ = self.channels.insert(channel, &[DIRTY]);
+        let _ = self.checkpoints.insert(minute(timestamp), &id.to_be_bytes());
+    }
+
+    /// Messages of `channel` are read from and written to the history of `original` from now on.
+    pub fn alias(&self, channel: &str, original: &str) {
+        let original = self.resolve(original);
+        let _ = self.aliases.insert(channel, original.as_bytes());
+    }
+
+    fn resolve(&self, channel: &str) -> String {
+        self.aliases
+            .get(channel)
+            .ok()
+            .flatten()
+            .map(|original| String::from_utf8_lossy(&original).into_owned())
+            .unwrap_or_else(|| channel.to_owned())
+    }
+
+    /// History stored before checkpoints existed is indexed once, on the first start after the upgrade.
+    fn backfill_checkpoints(&self) {
+        if !self.channels.is_empty() {
+            return;
+        }
+        for (key, value) in self.db.iter().flatten() {
+            let (Some(channel), Ok(message)) = (channel_of(&key), from_slice::<ChatMessage>(&value)) else {
+                continue;
+            };
+            self.mark(&channel, message.timestamp, message.id);
+        }
+    }
+
+    /// A retried message with a known nonce resolves to the stored copy instead of a duplicate.
+    pub fn find_nonce(&self, channel: &str, user: &str, nonce: &str) -> Option<ChatMessage> {
+        self.latest(channel, NONCE_WINDOW)
+            .into_iter()
+            .find(|message| message.user == user && message.nonce.as_deref() == Some(nonce))
+    }
+
+    pub fn set_access(&self, channel: &str, access: &ChatAccess) {
+        if let Ok(value) = to_vec(access) {
+            let _ = self.access.insert(channel, value);
+        }
+    }
+
+    /// Channels without stored rules predate them and stay public.
+    pub fn get_access(&self, channel: &str) -> ChatAccess {
+        self.access
+            .get(channel)
+            .ok()
+            .flatten()
+            .and_then(|value| from_slice::<ChatAccess>(&value).ok())
+            .unwrap_or(ChatAccess::Public)
+    }
+
+    pub fn get(&self, channel: &str, id: u64) -> Option<ChatMessage> {
+        self.db
+            .get(key(&self.resolve(channel), id))
+            .ok()
+            .flatten()
+            .and_then(|value| from_slice::<ChatMessage>(&value).ok())
+    }
+
+    pub fn latest(&self, channel: &str, count: usize) -> Vec<ChatMessage> {
+        self.page(channel, None, count)
+    }
+
+    /// Messages with an id lower than `before`, oldest first.
+    pub fn before(&self, channel: &str, before: Option<u64>) -> Vec<ChatMessage> {
+        self.page(channel, before, PAGE_LEN)
+    }
+
+    /// Drops messages past the retention window and trims channels over the size limit.
+    pub fn clean_up(&self) {
+        let expired = minute(chrono::Utc::now().timestamp() - RETENTION);
+        // every message up to the last id of an expired minute is expired as well
+        let bound = self
+            .checkpoints
+            .range(..expired)
+            .values()
+            .next_back()
+            .and_then(|value| value.ok())
+            .and_then(|value| value.as_ref().try_into().ok())
+            .map(u64::from_be_bytes);
+        for (channel, flag) in self.channels.iter().flatten() {
+            let channel = String::from_utf8_lossy(&channel).into_owned();
+            let start = prefix(&channel);
+            if let Some(bound) = bound {
+                let expired = self.db.range(start.as_bytes()..=key(&channel, bound).as_bytes()).keys();
+                for old in expired.flatten() {
+                    let _ = self.db.remove(old);
+                }
+            }
+            if flag.as_ref() == [DIRTY] {
+                let overflow = self.db.scan_prefix(&start).keys().rev().skip(MAX_MESSAGES_PER_CHANNEL);
+                for old in overflow.flatten() {
+                    let _ = self.db.remove(old);
+                }
+                let _ = self.channels.insert(channel.as_bytes(), &[CLEAN]);
+            }
+            if self.db.scan_prefix(&start).next().is_none() {
+                let _ = self.channels.remove(channel.as_bytes());
+            }
+        }
+        for old in self.checkpoints.range(..expired).keys().flatten() {
+            let _ = self.checkpoints.remove(old);
+        }
+    }
+
+    fn page(&self, channel: &str, before: Option<u64>, count: usize) -> Vec<ChatMessage> {
+        let channel = &self.resolve(channel);
+        let start = prefix(channel);
+        let end = match before {
+            Some(before) => key(channel, before),
+            None => format!("{}~", start),
+        };
+        let mut messages: Vec<ChatMessage> = self
+            .db
+            .range(start.as_bytes()..end.as_bytes())
+            .values()
+            .rev()
+            .flatten()
+            .filter_map(|value| from_slice::<ChatMessage>(&value).ok())
+            .take(count)
+            .collect();
+        messages.reverse();
+        messages
+    }
+}
+
+fn prefix(channel: &str) -> String {
+    format!("{channel}/")
+}
+
+fn key(channel: &str, id: u64) -> String {
+    format!("{}{:020}", prefix(channel), id)
+}
+
+fn minute(timestamp: i64) -> [u8; 8] {
+    (timestamp.max(0) / 60).to_be_bytes()
+}
+
+fn channel_of(key: &[u8]) -> Option<String> {
+    let key = std::str::from_utf8(key).ok()?;
+    key.rsplit_once('/').map(|(channel, _)| channel.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(id: u64) -> ChatFrame {
+        ChatFrame::Message(ChatMessage {
+            id,
+            user: "pl1".to_owned(),
+            message: "hi".to_owned(),
+            timestamp: 0,
+            nonce: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn lagging_chat_subscriber_gets_marker() {
+        let (sender, mut receiver) = broadcast::channel::<ChatFrame>(2);
+        for id in 0..5 {
+            sender.send(message(id)).unwrap();
+        }
+        assert!(matches!(
+            next_frame(&mut receiver).await,
+            Some(ChatFrame::Missed { count: 3 })
+        ));
+        for expected in 3..5 {
+            assert!(matches!(
+                next_frame(&mut receiver).await,
+                Some(ChatFrame::Message(ChatMessage { id, .. })) if id == expected
+            ));
+        }
+        drop(sender);
+        assert!(next_frame(&mut receiver).await.is_none());
+    }
+
+    #[test]
+    fn clean_up_drops_expired_messages_only() {
+        let history = ChatHistory::open(sled::Config::new().temporary(true).open().unwrap());
+        let text = |message: &str| ChatMessage {
+            id: 0,
+            user: "pl1".to_owned(),
+            message: message.to_owned(),
+            timestamp: 0,
+            nonce: None,
+        };
+        let old = history.push("room", text("old"));
+        history.checkpoints.clear().unwrap();
+        history.mark("room", chrono::Utc::now().timestamp() - RETENTION - 120, old.id);
+        history.push("room", text("new"));
+        history.push("other", text("elsewhere"));
+        history.clean_up();
+        let kept: Vec<String> = history
+            .latest("room", 10)
+            .into_iter()
+            .map(|message| message.message)
+            .collect();
+        assert_eq!(kept, ["new"]);
+        assert_eq!(history.latest("other", 10).len(), 1);
+        assert_eq!(history.checkpoints.len(), 1);
+    }
+}
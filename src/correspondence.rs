@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+
+use crate::quoridor::{MatchTracking, QuoridorMatch};
+
+#[derive(Serialize, Deserialize)]
+struct StoredMatch {
+    timestamp: i64,
+    game: QuoridorMatch,
+    #[serde(default)]
+    tracking: MatchTracking,
+}
+
+pub struct CorrespondenceGames {
+    db: sled::Db,
+}
+
+impl Default for CorrespondenceGames {
+    fn default() -> Self {
+        Self {
+            db: sled::open("correspondence").expect("Unable to start DB!"),
+        }
+    }
+}
+
+impl CorrespondenceGames {
+    pub fn save(&self, id: &str, game: &QuoridorMatch) {
+        let record = StoredMatch {
+            timestamp: game.get_timestamp(),
+            game: game.clone(),
+            tracking: game.tracking(),
+        };
+        if let Ok(value) = to_string(&record) {
+            let _ = self.db.insert(id, value.as_bytes());
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        let _ = self.db.remove(id);
+    }
+
+    pub fn load_all(&self) -> Vec<(String, QuoridorMatch)> {
+        self.db
+            .iter()
+            .flatten()
+            .filter_map(|(key, record)| {
+                let id = std::str::from_utf8(&key).ok()?.to_owned();
+                let mut stored = from_str::<StoredMatch>(std::str::from_utf8(&record).ok()?).ok()?;
+                stored.game.set_timestamp(stored.timestamp);
+                stored.game.set_tracking(stored.tracking);
+                Some((id, stored.game))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tokio::sync::broadcast;
+
+use crate::state::channel_capacity;
+
+const PAGE_LEN: usize = 50;
+const DIRECT_CAPACITY_VAR: &str = "DIRECT_CHANNEL_CAPACITY";
+const DEFAULT_DIRECT_CAPACITY: usize = 16;
+
+/// Stored message, participants are kept by email and never leave the server as such.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirectMessage {
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+    pub message: String,
+    pub timestamp: i64,
+}
+
+/// A direct message as clients see it, participants are public user ids.
+#[derive(Serialize, Clone, Debug)]
+pub struct DirectMessageView {
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+    pub message: String,
+    pub timestamp: i64,
+}
+
+/// `to` is the public id of the recipient.
+#[derive(Deserialize)]
+pub struct DirectPost {
+    pub to: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Conversation {
+    pub user_id: String,
+    pub username: String,
+    pub last: Option<DirectMessageView>,
+    pub unread: u64,
+}
+
+/// One-to-one conversations keyed by the sorted pair of participants, plus unread counters per reader.
+/// The counters tree also serves as the list of conversations a user has.
+pub struct DirectMessages {
+    db: sled::Db,
+    unread: sled::Tree,
+}
+
+impl Default for DirectMessages {
+    fn default() -> Self {
+        let db = sled::open("direct_messages").expect("Unable to start DB!");
+        Self {
+            unread: db.open_tree("unread").expect("Unable to start DB!"),
+            db,
+        }
+    }
+}
+
+impl DirectMessages {
+    pub fn push(&self, from: &str, to: &str, message: String) -> DirectMessage {
+        let message = DirectMessage {
+            id: self.db.generate_id().unwrap_or_default(),
+            from: from.to_owned(),
+            to: to.to_owned(),
+            message,
+            timestamp: chrono::Utc::now().timestamp(),
+        };
+        if let Ok(value) = to_vec(&message) {
+            let _ = self.db.insert(message_key(from, to, message.id), value);
+        }
+        let _ = self.unread.fetch_and_update(counter_key(to, from), |count| {
+            Some((read_count(count) + 1).to_be_bytes().to_vec())
+        });
+        let _ = self.unread.fetch_and_update(counter_key(from, to), |count| {
+            Some(read_count(count).to_be_bytes().to_vec())
+        });
+        message
+    }
+
+    /// Messages with an id lower than `before`, oldest first.
+    pub fn conversation(&self, user: &str, other: &str, before: Option<u64>) -> Vec<DirectMessage> {
+        let start = conversation_prefix(user, other);
+        let end = match before {
+            Some(before) => message_key(user, other, before),
+            None => format!("{start}~"),
+        };
+        let mut messages: Vec<DirectMessage> = self
+            .db
+            .range(start.as_bytes()..end.as_bytes())
+            .values()
+            .rev()
+            .flatten()
+            .filter_map(|value| from_slice::<DirectMessage>(&value).ok())
+            .take(PAGE_LEN)
+            .collect();
+        messages.reverse();
+        messages
+    }
+
+    pub fn mark_read(&self, user: &str, other: &str) {
+        if self.unread.contains_key(counter_key(user, other)).unwrap_or(false) {
+            let _ = self.unread.insert(counter_key(user, other), &0u64.to_be_bytes());
+        }
+    }
+
+    /// Conversation partners with their unread counts.
+    pub fn partners(&self, user: &str) -> Vec<(String, u64)> {
+        let prefix = counter_key(user, "");
+        self.unread
+            .scan_prefix(prefix.as_bytes())
+            .flatten()
+            .filter_map(|(key, count)| {
+                let other = std::str::from_utf8(&key).ok()?.strip_prefix(&prefix)?.to_owned();
+                Some((other, read_count(Some(&count))))
+            })
+            .collect()
+    }
+
+    pub fn last(&self, user: &str, other: &str) -> Option<DirectMessage> {
+        self.db
+            .scan_prefix(conversation_prefix(user, other).as_bytes())
+            .values()
+            .next_back()
+            .and_then(|value| value.ok())
+            .and_then(|value| from_slice::<DirectMessage>(&value).ok())
+    }
+}
+
+/// Live direct message sockets per user, messages reach every open tab of both participants.
+#[derive(Default)]
+pub struct DirectRelay {
+    channels: HashMap<String, broadcast::Sender<DirectMessage>>,
+}
+
+impl DirectRelay {
+    pub fn subscribe(&mut self, user: &str) -> broadcast::Receiver<DirectMessage> {
+        self.channels
+            .entry(user.to_owned())
+            .or_insert_with(|| {
+                broadcast::channel::<DirectMessage>(channel_capacity(DIRECT_CAPACITY_VAR, DEFAULT_DIRECT_CAPACITY)).0
+            })
+            .subscribe()
+    }
+
+    pub fn deliver(&self, message: &DirectMessage) {
+        for user in [&message.from, &message.to] {
+            if let Some(channel) = self.channels.get(user) {
+                let _ = channel.send(message.clone());
+            }
+        }
+    }
+
+    /// Drops channels of users without any connected socket.
+    pub fn clean_up(&mut self) {
+        self.channels.retain(|_, channel| channel.receiver_count() > 0);
+    }
+}
+
+fn conversation_prefix(user: &str, other: &str) -> String {
+    let (first, second) = if user < other { (user, other) } else { (other, user) };
+    format!("{first} {second}/")
+}
+
+fn message_key(user: &str, other: &str, id: u64) -> String {
+    format!("{}{:020}", conversation_prefix(user, other), id)
+}
+
+fn counter_key(reader: &str, other: &str) -> String {
+    format!("{reader} {other}")
+}
+
+fn read_count(count: Option<&[u8]>) -> u64 {
+    count
+        .and_then(|count| count.try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
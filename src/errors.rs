@@ -1,5 +1,7 @@
 use serde::Serialize;
 
+use crate::moderation::ChatRejection;
+
 #[derive(Debug, Serialize, Clone)]
 pub enum StateError {
     Unauthorized,
@@ -7,4 +9,5 @@ pub enum StateError {
     AlreadyTaken,
     ServerError,
     UnsupportedDataType(String),
+    Rejected(ChatRejection),
 }
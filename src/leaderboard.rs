@@ -5,39 +5,95 @@ use serde_json::{from_str, to_string};
 
 use crate::{
     errors::StateError,
-    messages::UserContext,
-    quoridor::{cpu::CPU, QuoridorMatch},
+    quoridor::{
+        cpu::{CpuDifficulty, CPU},
+        Outcome, QuoridorMatch,
+    },
 };
 
+pub const DEFAULT_RATING: i32 = 1200;
+const RATING_K_FACTOR: f64 = 32.0;
+const HISTORY_LEN: usize = 20;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserLeaderBoard {
     pub username: String,
     pub wins: i32,
     pub loses: i32,
+    #[serde(default)]
+    pub draws: i32,
+    #[serde(default = "default_rating")]
+    pub rating: i32,
+}
+
+impl UserLeaderBoard {
+    /// Points counted in halves, a draw is worth half a win.
+    fn half_points(&self) -> i64 {
+        2 * self.wins as i64 + self.draws as i64
+    }
+
+    fn games(&self) -> i64 {
+        (self.wins + self.loses + self.draws) as i64
+    }
+
+    /// Record of a user who has not finished a rated game yet.
+    pub fn new(username: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+            wins: 0,
+            loses: 0,
+            draws: 0,
+            rating: DEFAULT_RATING,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchSummary {
+    pub opponent: String,
+    pub outcome: Outcome,
+    pub turns: usize,
+    pub finished: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserCpuRecord {
+    pub username: String,
+    /// Records from before difficulties existed were all played against the `Hard` CPU.
+    #[serde(default)]
+    pub difficulty: CpuDifficulty,
+    pub wins: i32,
+    pub loses: i32,
+    #[serde(default)]
+    pub draws: i32,
+    pub fewest_turns_win: Option<usize>,
 }
 
 pub struct LeaderBoard {
     db: sled::Db,
+    cpu_db: sled::Tree,
+    history_db: sled::Tree,
 }
 
 impl Default for LeaderBoard {
     fn default() -> Self {
-        Self {
-            db: sled::open("games").expect("Unable to start DB!"),
-        }
+        let db = sled::open("games").expect("Unable to start DB!");
+        let leaderboard = Self {
+            cpu_db: db.open_tree("cpu_challenge").expect("Unable to start DB!"),
+            history_db: db.open_tree("history").expect("Unable to start DB!"),
+            db,
+        };
+        leaderboard.migrate_cpu_records();
+        leaderboard
     }
 }
 
 impl LeaderBoard {
     pub fn get_full_leader_board(&self) -> Vec<UserLeaderBoard> {
         let mut board: Vec<UserLeaderBoard> = self.get().into_iter().filter(|data| data.wins > data.loses).collect();
-        board.sort_unstable_by(|a, b| {
-            let order = b.wins.cmp(&a.wins);
-            match order {
-                Ordering::Equal => (b.wins / b.loses).cmp(&(a.wins / a.loses)),
-                _ => order,
-            }
-        });
+        board.sort_unstable_by(rank);
         board.truncate(30);
         board
     }
@@ -64,51 +120,235 @@ impl LeaderBoard {
         from_str(serialized_record).map_err(|_| StateError::ServerError)
     }
 
-    pub fn process_game(&self, user_context: &UserContext, snapshot: &QuoridorMatch) {
-        if user_context.username == "GUEST" || snapshot.contains_player(CPU) {
+    pub fn get_cpu_leader_board(&self, difficulty: CpuDifficulty) -> Vec<UserCpuRecord> {
+        let mut board: Vec<UserCpuRecord> = self
+            .cpu_db
+            .scan_prefix(cpu_key(difficulty, ""))
+            .flatten()
+            .filter_map(|(_, record)| {
+                std::str::from_utf8(&record)
+                    .ok()
+                    .and_then(|data| from_str::<UserCpuRecord>(data).ok())
+            })
+            .filter(|record| record.wins > 0)
+            .collect();
+        board.sort_unstable_by(|a, b| {
+            let order = b.wins.cmp(&a.wins);
+            match order {
+                Ordering::Equal => a.fewest_turns_win.cmp(&b.fewest_turns_win),
+                _ => order,
+            }
+        });
+        board.truncate(30);
+        board
+    }
+
+    /// One record for every difficulty the user has played.
+    pub fn get_cpu_records_by_email(&self, email: &str) -> Vec<UserCpuRecord> {
+        CpuDifficulty::ALL
+            .into_iter()
+            .filter_map(|difficulty| self.get_cpu_record(email, difficulty).ok())
+            .collect()
+    }
+
+    fn get_cpu_record(&self, email: &str, difficulty: CpuDifficulty) -> Result<UserCpuRecord, StateError> {
+        let record = self
+            .cpu_db
+            .get(cpu_key(difficulty, email))
+            .map_err(|_| StateError::ServerError)?
+            .ok_or(StateError::NotFound)?;
+        let serialized_record = std::str::from_utf8(&record).map_err(|_| StateError::ServerError)?;
+        from_str(serialized_record).map_err(|_| StateError::ServerError)
+    }
+
+    pub fn get_rating(&self, email: &str) -> i32 {
+        self.get_by_email(email)
+            .map(|record| record.rating)
+            .unwrap_or(DEFAULT_RATING)
+    }
+
+    pub fn get_history(&self, email: &str) -> Vec<MatchSummary> {
+        self.history_db
+            .get(email)
+            .ok()
+            .flatten()
+            .and_then(|record| {
+                std::str::from_utf8(&record)
+                    .ok()
+                    .and_then(|data| from_str::<Vec<MatchSummary>>(data).ok())
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn record_match(&self, email: &str, summary: MatchSummary) {
+        let mut history = self.get_history(email);
+        history.insert(0, summary);
+        history.truncate(HISTORY_LEN);
+        if let Ok(value) = to_string(&history) {
+            let _ = self.history_db.insert(email, value.as_bytes());
+        }
+    }
+
+    /// Counts the finished match for the registered players in it, given as email and username.
+    /// Both rating changes come from the ratings before the game, so every rated game is zero-sum.
+    pub fn process_game(&self, players: &[(String, String)], snapshot: &QuoridorMatch) {
+        if snapshot.contains_player(CPU) {
+            for (email, username) in players {
+                self.process_cpu_game(email, username, snapshot);
+            }
             return;
         }
-        if let Some(winner) = &snapshot.winner {
-            if &user_context.email == winner {
-                self.add_win(user_context);
-            } else {
-                self.add_lose(user_context)
+        if snapshot.settings.casual {
+            return;
+        }
+        let changes: Vec<_> = players
+            .iter()
+            .filter_map(|(email, username)| {
+                let outcome = snapshot.outcome_for(email)?;
+                let opponent_rating = self.get_rating(snapshot.opponent_of(email));
+                let change = rating_change(self.get_rating(email), opponent_rating, outcome.score());
+                Some((email, username, outcome, change))
+            })
+            .collect();
+        for (email, username, outcome, change) in changes {
+            self.add_result(email, username, outcome, change);
+        }
+    }
+
+    /// Games with a takeback are left out, the CPU grants them without asking.
+    fn process_cpu_game(&self, email: &str, username: &str, snapshot: &QuoridorMatch) {
+        let outcome = match snapshot.outcome_for(email) {
+            Some(outcome) if !snapshot.had_takeback() => outcome,
+            _ => return,
+        };
+        let difficulty = snapshot.settings.difficulty;
+        let mut record = self.get_cpu_record(email, difficulty).unwrap_or(UserCpuRecord {
+            username: username.to_owned(),
+            difficulty,
+            wins: 0,
+            loses: 0,
+            draws: 0,
+            fewest_turns_win: None,
+        });
+        match outcome {
+            Outcome::Win => {
+                record.wins += 1;
+                let turns = snapshot.turns();
+                if record.fewest_turns_win.is_none_or(|best| turns < best) {
+                    record.fewest_turns_win = Some(turns);
+                }
             }
+            Outcome::Loss => record.loses += 1,
+            Outcome::Draw => record.draws += 1,
+        }
+        if let Ok(value) = to_string(&record) {
+            let _ = self.cpu_db.insert(cpu_key(difficulty, email), value.as_bytes());
         }
     }
 
-    fn add_win(&self, user: &UserContext) {
-        let record = if let Ok(mut record) = self.get_by_email(&user.email) {
-            record.wins += 1;
-            record
-        } else {
-            UserLeaderBoard {
-                username: user.username.to_owned(),
-                wins: 1,
-                loses: 0,
+    /// Records used to be keyed by email alone, back when there was only the `Hard` CPU.
+    fn migrate_cpu_records(&self) {
+        let legacy: Vec<_> = self
+            .cpu_db
+            .iter()
+            .flatten()
+            .filter(|(key, _)| !key.contains(&b'/'))
+            .collect();
+        for (key, record) in legacy {
+            if let Ok(email) = std::str::from_utf8(&key) {
+                let _ = self.cpu_db.insert(cpu_key(CpuDifficulty::Hard, email), record);
+                let _ = self.cpu_db.remove(&key);
             }
-        };
+        }
+    }
+
+    fn add_result(&self, email: &str, username: &str, outcome: Outcome, rating_change: i32) {
+        let mut record = self
+            .get_by_email(email)
+            .unwrap_or_else(|_| UserLeaderBoard::new(username));
+        match outcome {
+            Outcome::Win => record.wins += 1,
+            Outcome::Loss => record.loses += 1,
+            Outcome::Draw => record.draws += 1,
+        }
+        record.rating += rating_change;
         if let Ok(value) = to_string(&record) {
-            let _ = self.db.insert(&user.email, value.as_bytes());
+            let _ = self.db.insert(email, value.as_bytes());
         }
     }
+}
 
-    fn add_lose(&self, user: &UserContext) {
-        if user.username == "GUEST" {
-            return;
+/// Most points first, ties go to the better score per game.
+fn rank(a: &UserLeaderBoard, b: &UserLeaderBoard) -> Ordering {
+    b.half_points()
+        .cmp(&a.half_points())
+        .then_with(|| (b.half_points() * a.games()).cmp(&(a.half_points() * b.games())))
+}
+
+fn cpu_key(difficulty: CpuDifficulty, email: &str) -> String {
+    format!("{difficulty:?}/{email}")
+}
+
+fn default_rating() -> i32 {
+    DEFAULT_RATING
+}
+
+/// Elo update, `score` comes from `Outcome::score`.
+fn rating_change(rating: i32, opponent_rating: i32, score: f64) -> i32 {
+    let expected = 1.0 / (1.0 + 10f64.powf((opponent_rating - rating) as f64 / 400.0));
+    (RATING_K_FACTOR * (score - expected)).round() as i32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::PlayerMove;
+    use crate::quoridor::MatchSettings;
+
+    fn leaderboard() -> LeaderBoard {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        LeaderBoard {
+            cpu_db: db.open_tree("cpu_challenge").unwrap(),
+            history_db: db.open_tree("history").unwrap(),
+            db,
         }
-        let record = if let Ok(mut record) = self.get_by_email(&user.email) {
-            record.loses += 1;
-            record
-        } else {
-            UserLeaderBoard {
-                username: user.username.to_owned(),
-                wins: 0,
-                loses: 1,
-            }
+    }
+
+    #[test]
+    fn ranks_by_points_with_draws() {
+        let record = |username: &str, wins, loses, draws| UserLeaderBoard {
+            wins,
+            loses,
+            draws,
+            ..UserLeaderBoard::new(username)
         };
-        if let Ok(value) = to_string(&record) {
-            let _ = self.db.insert(&user.email, value.as_bytes());
+        let mut board = [
+            record("unbeaten", 3, 0, 0),
+            record("drawish", 3, 1, 2),
+            record("busy", 4, 3, 0),
+        ];
+        board.sort_unstable_by(rank);
+        let order: Vec<&str> = board.iter().map(|record| record.username.as_str()).collect();
+        assert_eq!(order, vec!["drawish", "busy", "unbeaten"]);
+    }
+
+    #[test]
+    fn rated_games_are_zero_sum() {
+        let leaderboard = leaderboard();
+        let players = [
+            ("pl1".to_owned(), "one".to_owned()),
+            ("pl2".to_owned(), "two".to_owned()),
+        ];
+        let lobby = [players[0].0.to_owned(), players[1].0.to_owned()];
+        for loser in ["pl2", "pl2", "pl1"] {
+            let mut game = QuoridorMatch::new(&lobby, MatchSettings::default());
+            game.make_move(PlayerMove::Concede, loser);
+            leaderboard.process_game(&players, &game);
+            assert_eq!(
+                leaderboard.get_rating("pl1") + leaderboard.get_rating("pl2"),
+                2 * DEFAULT_RATING
+            );
         }
+        assert_eq!(leaderboard.get_by_email("pl1").unwrap().wins, 2);
     }
 }
@@ -1,32 +1,55 @@
+mod achievements;
+mod archive;
 mod auth;
+mod challenges;
+mod chat;
+mod correspondence;
+mod direct;
 mod errors;
 mod leaderboard;
+mod matchmaking;
 mod messages;
+mod moderation;
+mod presence;
 mod quoridor;
+mod social;
 mod state;
+mod tournament;
 //internals
+use challenges::{Challenge, ChallengeCreate};
+use direct::{Conversation, DirectMessageView, DirectPost};
 use errors::StateError;
-use leaderboard::UserLeaderBoard;
+use leaderboard::{UserCpuRecord, UserLeaderBoard};
 use messages::{
-    ChatMessage, GuestLogin, PlayerMove, PlayerMoveResult, QuoridorMatchMeta, UserContext, UserCreate, UserLogin,
+    ChatCommand, ChatFrame, ChatHistoryQuery, ChatMessage, ChatPost, ChatReply, ChatSocketQuery, CpuQuery,
+    GameClientFrame, GameError, GameNotification, GameServerFrame, GameSnapshot, GuestLogin, HostOptions, LobbyEvent,
+    MatchReplay, MatchRequest, MoveResponse, PersonalStats, PlayerMove, PlayerMoveResult, PrivacySettings,
+    PublicProfile, QueueHost, QuoridorMatchMeta, SnapshotQuery, SocialOverview, SystemEvent, UserContext, UserCreate,
+    UserLogin, UserMatch, WaitQuery, PROTOCOL_VERSION,
 };
-use state::AppState;
+use moderation::{ChatReport, MuteCreate, ReportCreate};
+use presence::{Activity, Presence};
+use quoridor::{LegalMoves, MatchSettings};
+use state::{game_update, AppState, GameUpdate};
+use tournament::{TournamentCreate, TournamentView};
 //std
 use std::sync::Arc;
 // extern creates
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{delete, get, post};
 use axum::{Json, Router};
 use futures::{sink::SinkExt, stream::StreamExt};
 use serde_json::{from_str, to_string};
+use tokio::sync::broadcast::error::RecvError;
 use tower_cookies::{Cookie, CookieManagerLayer, Cookies};
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 const TOKEN: &str = "auth_token";
+const LONG_POLL_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(30);
 
 async fn login(
     State(app_state): State<Arc<AppState>>,
@@ -35,7 +58,7 @@ async fn login(
 ) -> Result<UserContext, StateError> {
     let mut user = app_state.user_get_with_session(&payload.email, &payload.password)?;
     cookies.add(Cookie::new(TOKEN.to_owned(), user.auth_token.to_owned()));
-    user.active_match = app_state.quoridor_get_id_by_player(&user.email);
+    app_state.quoridor_fill_matches(&mut user);
     Ok(user)
 }
 
@@ -67,7 +90,7 @@ async fn create_user(
 
 async fn auth_context(State(app_state): State<Arc<AppState>>, cookies: Cookies) -> Result<UserContext, StateError> {
     let mut user = app_state.get_session(cookies.get(TOKEN))?;
-    user.active_match = app_state.quoridor_get_id_by_player(&user.email);
+    app_state.quoridor_fill_matches(&mut user);
     Ok(user)
 }
 
@@ -75,17 +98,187 @@ async fn leaderboard(State(app_state): State<Arc<AppState>>) -> Json<Vec<UserLea
     app_state.leaderboard.lock().unwrap().get_full_leader_board().into()
 }
 
+async fn cpu_leaderboard(
+    Query(query): Query<CpuQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Json<Vec<UserCpuRecord>> {
+    app_state
+        .leaderboard
+        .lock()
+        .unwrap()
+        .get_cpu_leader_board(query.difficulty)
+        .into()
+}
+
 async fn get_personal_stats(
     State(app_state): State<Arc<AppState>>,
     cookies: Cookies,
-) -> Result<UserLeaderBoard, StateError> {
+) -> Result<PersonalStats, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    let record = match app_state.leaderboard.lock().unwrap().get_by_email(&user.email) {
+        Err(StateError::NotFound) => UserLeaderBoard::new(&user.username),
+        record => record?,
+    };
+    let cpu = app_state
+        .leaderboard
+        .lock()
+        .unwrap()
+        .get_cpu_records_by_email(&user.email);
+    let achievements = app_state.achievements.lock().unwrap().get_by_email(&user.email);
+    Ok(PersonalStats {
+        record,
+        achievements,
+        cpu,
+    })
+}
+
+async fn user_profile(
+    Path(user_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<PublicProfile, StateError> {
+    app_state.user_public_profile(&user_id)
+}
+
+async fn user_privacy(
+    State(app_state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Json(payload): Json<PrivacySettings>,
+) -> Result<StatusCode, StateError> {
     let user = app_state.get_session(cookies.get(TOKEN))?;
-    app_state.leaderboard.lock().unwrap().get_by_email(&user.email)
+    app_state.users.lock().unwrap().set_privacy(&user.email, payload)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_overview(
+    State(app_state): State<Arc<AppState>>,
+    cookies: Cookies,
+) -> Result<SocialOverview, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.social_overview(&user.email))
+}
+
+/// Social actions are for registered users only and target another account by its public id.
+/// Returns the emails of the user and the target.
+fn social_pair(app_state: &AppState, cookies: &Cookies, target_id: &str) -> Result<(String, String), StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if user.username == "GUEST" {
+        return Err(StateError::Unauthorized);
+    }
+    let target = app_state.users.lock().unwrap().get_public(target_id)?;
+    Ok((user.email, target.email))
+}
+
+async fn social_friend_add(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().send_request(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_friend_accept(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().accept_request(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_friend_decline(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().decline_request(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_friend_remove(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().remove_friend(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_follow(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().follow(&user, &target)?;
+    Ok(StatusCode::OK)
 }
 
-async fn quoridor_cpu(cookies: Cookies, State(app_state): State<Arc<AppState>>) -> Result<UserContext, StateError> {
+async fn social_unfollow(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().unfollow(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_block(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().block(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_unblock(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().unblock(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_mute(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().mute(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn social_unmute(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let (user, target) = social_pair(&app_state, &cookies, &id)?;
+    app_state.social.lock().unwrap().unmute(&user, &target)?;
+    Ok(StatusCode::OK)
+}
+
+async fn quoridor_cpu(
+    cookies: Cookies,
+    Query(query): Query<CpuQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<UserContext, StateError> {
     let mut user = app_state.get_session(cookies.get(TOKEN))?;
-    user.active_match = app_state.quoridor_new_game(&vec![user.email.to_owned()]);
+    let settings = MatchSettings {
+        difficulty: query.difficulty,
+        ..MatchSettings::default()
+    };
+    user.active_match = app_state.quoridor_new_game_with_settings(&[user.email.to_owned()], settings);
     Ok(user)
 }
 
@@ -98,13 +291,22 @@ async fn quoridor_que_join(
     if user.email == host_name {
         return Err(StateError::UnsupportedDataType("Same user".into()));
     }
-    let sender = app_state
-        .quoridor_que
-        .lock()
-        .unwrap()
-        .remove(&host_name)
-        .ok_or(StateError::NotFound)?;
-    user.active_match = app_state.quoridor_new_game(&vec![host_name, user.email.to_owned()]);
+    if app_state.social_is_blocked(&user.email, &host_name) {
+        return Err(StateError::Unauthorized);
+    }
+    let rating = app_state.leaderboard.lock().unwrap().get_rating(&user.email);
+    let (entry, sender) = {
+        let mut que = app_state.quoridor_que.lock().unwrap();
+        let (entry, _) = que.get(&host_name).ok_or(StateError::NotFound)?;
+        if !entry.accepts(rating) {
+            return Err(StateError::UnsupportedDataType("Rating out of range".into()));
+        }
+        que.remove(&host_name).ok_or(StateError::NotFound)?
+    };
+    app_state.lobby_events.send(LobbyEvent::HostRemoved {
+        host: host_name.to_owned(),
+    });
+    user.active_match = app_state.quoridor_new_game_with_settings(&[host_name, user.email.to_owned()], entry.settings);
     if let Some(game) = &user.active_match {
         match sender.send(game.to_owned()) {
             Ok(_) => return Ok(user),
@@ -114,13 +316,21 @@ async fn quoridor_que_join(
     Err(StateError::ServerError)
 }
 
-async fn quoridor_que_host(cookies: Cookies, ws: WebSocketUpgrade, State(app_state): State<Arc<AppState>>) -> Response {
+async fn quoridor_que_host(
+    cookies: Cookies,
+    ws: WebSocketUpgrade,
+    Query(options): Query<HostOptions>,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
     let player = match app_state.get_session(cookies.get(TOKEN)) {
         Ok(player) => player.email,
         Err(error) => return error.into_response(),
     };
+    let rating = app_state.leaderboard.lock().unwrap().get_rating(&player);
+    let entry = QueueHost::new(&player, rating, options);
 
     ws.on_upgrade(|socket| async move {
+        let _presence = Presence::connect(&app_state.presence, &player, Activity::InQueue);
         let (channel_send, channel_recv) = tokio::sync::oneshot::channel::<String>();
         let (mut sender, mut reciever) = socket.split();
 
@@ -128,7 +338,8 @@ async fn quoridor_que_host(cookies: Cookies, ws: WebSocketUpgrade, State(app_sta
             .quoridor_que
             .lock()
             .unwrap()
-            .insert(player.to_owned(), channel_send);
+            .insert(player.to_owned(), (entry.clone(), channel_send));
+        app_state.lobby_events.send(LobbyEvent::HostAdded(entry));
 
         let mut send_task = tokio::spawn(async move {
             if let Ok(game_id) = channel_recv.await {
@@ -143,26 +354,244 @@ async fn quoridor_que_host(cookies: Cookies, ws: WebSocketUpgrade, State(app_sta
             }
         }
 
-        app_state.quoridor_que.lock().unwrap().remove(&player);
+        if app_state.quoridor_que.lock().unwrap().remove(&player).is_some() {
+            app_state.lobby_events.send(LobbyEvent::HostRemoved { host: player });
+        }
+    })
+}
+
+async fn quoridor_matchmaking(
+    cookies: Cookies,
+    ws: WebSocketUpgrade,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
+    let player = match app_state.get_session(cookies.get(TOKEN)) {
+        Ok(player) => player.email,
+        Err(error) => return error.into_response(),
+    };
+
+    ws.on_upgrade(|socket| async move {
+        let _presence = Presence::connect(&app_state.presence, &player, Activity::InQueue);
+        let (mut sender, mut reciever) = socket.split();
+        let mut channel_recv = match app_state.matchmaking_join(&player) {
+            Ok(channel_recv) => channel_recv,
+            Err(error) => {
+                if let Ok(msg) = to_string(&error) {
+                    let _ = sender.send(msg.into()).await;
+                }
+                return;
+            }
+        };
+
+        let state = app_state.clone();
+        let mut send_task = tokio::spawn(async move {
+            while let Some(game_id) = channel_recv.recv().await {
+                if state.matchmaking_confirm(&game_id) {
+                    let _ = sender.send(game_id.into()).await;
+                    break;
+                }
+            }
+        });
+
+        tokio::select! {
+            _tx_s = (&mut send_task) => {},
+            _tx_r = (&mut reciever.next()) => {
+                send_task.abort()
+            }
+        }
+
+        app_state.matchmaking.lock().unwrap().leave(&player);
     })
 }
 
 async fn quoridor_que_get(
     cookies: Cookies,
     State(app_state): State<Arc<AppState>>,
-) -> Result<Json<Vec<String>>, StateError> {
+) -> Result<Json<Vec<QueueHost>>, StateError> {
     app_state.get_session(cookies.get(TOKEN))?;
     let que: Json<_> = app_state
         .quoridor_que
         .lock()
         .unwrap()
-        .keys()
-        .map(|key| key.to_owned())
+        .values()
+        .map(|(entry, _)| entry.clone())
         .collect::<Vec<_>>()
         .into();
     Ok(que)
 }
 
+async fn challenge_create(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<ChallengeCreate>,
+) -> Result<Json<Challenge>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.challenge_create(&user, payload)?.into())
+}
+
+async fn challenge_pending(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Challenge>>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    let pending = app_state.challenges.lock().unwrap().pending_for(&user.email);
+    Ok(pending.into())
+}
+
+async fn challenge_get(
+    cookies: Cookies,
+    Path(code): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Challenge>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    let challenge = app_state.challenges.lock().unwrap().get(&code, &user.email)?;
+    Ok(challenge.into())
+}
+
+async fn challenge_accept(
+    cookies: Cookies,
+    Path(code): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<UserContext, StateError> {
+    let mut user = app_state.get_session(cookies.get(TOKEN))?;
+    user.active_match = Some(app_state.challenge_accept(&code, &user.email)?);
+    Ok(user)
+}
+
+async fn challenge_cancel(
+    cookies: Cookies,
+    Path(code): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    app_state.challenges.lock().unwrap().cancel(&code, &user.email)?;
+    Ok(StatusCode::OK)
+}
+
+async fn tournament_list(State(app_state): State<Arc<AppState>>) -> Json<Vec<TournamentView>> {
+    let tournaments: Vec<TournamentView> = app_state
+        .tournaments
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(tournament, _)| tournament.into())
+        .collect();
+    tournaments.into()
+}
+
+async fn tournament_create(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<TournamentCreate>,
+) -> Result<Json<TournamentView>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if user.username == "GUEST" {
+        return Err(StateError::Unauthorized);
+    }
+    Ok(app_state.tournament_create(&user.email, payload).into())
+}
+
+async fn tournament_get(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<TournamentView>, StateError> {
+    Ok(app_state.tournament_get(&id)?.into())
+}
+
+async fn tournament_register(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<TournamentView>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if user.username == "GUEST" {
+        return Err(StateError::Unauthorized);
+    }
+    Ok(app_state.tournament_register(&id, &user.email)?.into())
+}
+
+async fn tournament_start(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<TournamentView>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.tournament_start(&id, &user.email)?.into())
+}
+
+async fn tournament_events(
+    ws: WebSocketUpgrade,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
+    let (view, mut channel_recv) = match app_state.tournament_subscribe(&id) {
+        Some(subscription) => subscription,
+        None => return StateError::NotFound.into_response(),
+    };
+
+    ws.on_upgrade(|socket: WebSocket| async move {
+        let (mut sender, mut reciever) = socket.split();
+
+        let mut send_task = tokio::spawn(async move {
+            if let Ok(snapshot) = to_string(&view) {
+                let _ = sender.send(snapshot.into()).await;
+            }
+            while let Ok(view) = channel_recv.recv().await {
+                if let Ok(snapshot) = to_string(&view) {
+                    let _ = sender.send(snapshot.into()).await;
+                }
+            }
+        });
+
+        tokio::select! {
+            _tx_s = (&mut send_task) => {},
+            _tx_r = (&mut reciever.next()) => {
+                send_task.abort()
+            }
+        }
+    })
+}
+
+async fn lobby_events(cookies: Cookies, ws: WebSocketUpgrade, State(app_state): State<Arc<AppState>>) -> Response {
+    let player = match app_state.get_session(cookies.get(TOKEN)) {
+        Ok(player) => player.email,
+        Err(error) => return error.into_response(),
+    };
+
+    ws.on_upgrade(|socket: WebSocket| async move {
+        let _presence = Presence::connect(&app_state.presence, &player, Activity::Online);
+        let mut channel_recv = app_state.lobby_events.subscribe();
+        let online = LobbyEvent::OnlinePlayers(app_state.online_count());
+        let (mut sender, mut reciever) = socket.split();
+
+        let mut send_task = tokio::spawn(async move {
+            if let Ok(msg) = to_string(&online) {
+                let _ = sender.send(msg.into()).await;
+            }
+            while let Ok(event) = channel_recv.recv().await {
+                if let Ok(msg) = to_string(&event) {
+                    let _ = sender.send(msg.into()).await;
+                }
+            }
+        });
+
+        tokio::select! {
+            _tx_s = (&mut send_task) => {},
+            _tx_r = (&mut reciever.next()) => {
+                send_task.abort()
+            }
+        }
+    })
+}
+
+async fn quoridor_my_matches(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<UserMatch>>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.quoridor_get_user_matches(&user.email).into())
+}
+
 async fn quoridor_get_matches(
     cookies: Cookies,
     State(app_state): State<Arc<AppState>>,
@@ -173,7 +602,10 @@ async fn quoridor_get_matches(
         .lock()
         .unwrap()
         .iter()
-        .map(|(key, (game, _))| (key.to_owned(), game.read().unwrap().clone()).into())
+        .filter_map(|(key, (game, _))| {
+            let game = game.read().unwrap();
+            (!game.settings.private).then(|| (key.to_owned(), game.clone()).into())
+        })
         .collect();
     Ok(data.into())
 }
@@ -182,6 +614,7 @@ async fn join_chat(
     cookies: Cookies,
     ws: WebSocketUpgrade,
     Path(id): Path<String>,
+    Query(options): Query<ChatSocketQuery>,
     State(app_state): State<Arc<AppState>>,
 ) -> Response {
     let session = app_state.get_session(cookies.get(TOKEN));
@@ -190,34 +623,196 @@ async fn join_chat(
     }
     let player = session.unwrap().email;
 
-    let channel_send = if let Some(channel) = app_state.chat_channel.read().unwrap().get(&id) {
+    let channel = if let Some(channel) = app_state.chat_channel.read().unwrap().get(&id) {
         channel.clone()
     } else {
         return StateError::NotFound.into_response();
     };
+    if !channel.access.can_read(&player) {
+        return StateError::Unauthorized.into_response();
+    }
+    let frames = options.frames;
 
-    ws.on_upgrade(|socket: WebSocket| async move {
-        let mut channel_recv = channel_send.subscribe();
+    ws.on_upgrade(move |socket: WebSocket| async move {
+        let _presence = Presence::connect(&app_state.presence, &player, Activity::Online);
+        // mutes and blocks can change while connected, the watch follows them
+        let hidden = app_state.social.lock().unwrap().watch_hidden(&player);
+        let mut channel_recv = channel.sender.subscribe();
+        let replay = app_state.chat_history.lock().unwrap().latest(&id, chat::REPLAY_LEN);
         let (mut sender, mut reciever) = socket.split();
 
+        let (direct_send, mut direct_recv) = tokio::sync::mpsc::channel::<ChatReply>(16);
+
         let mut send_task = tokio::spawn(async move {
-            while let Ok(message) = channel_recv.recv().await {
-                if let Ok(json_msg) = to_string(&message) {
+            let hidden_now = hidden.borrow().clone();
+            for message in replay.into_iter().filter(|message| !hidden_now.contains(&message.user)) {
+                if let Some(json_msg) = ChatFrame::Message(message).encode(frames) {
                     let _ = sender.send(json_msg.into()).await;
                 }
             }
+            loop {
+                tokio::select! {
+                    frame = chat::next_frame(&mut channel_recv) => {
+                        let frame = match frame {
+                            Some(frame) => frame,
+                            None => return,
+                        };
+                        if let Some(author) = frame.author() {
+                            if hidden.borrow().iter().any(|hidden| hidden == author) {
+                                continue;
+                            }
+                        }
+                        if let Some(json_msg) = frame.encode(frames) {
+                            let _ = sender.send(json_msg.into()).await;
+                        }
+                    },
+                    Some(reply) = direct_recv.recv() => {
+                        // acknowledgements are part of the framed protocol
+                        if !frames {
+                            continue;
+                        }
+                        if let Ok(json_msg) = to_string(&reply) {
+                            let _ = sender.send(json_msg.into()).await;
+                        }
+                    }
+                }
+            }
         });
         let mut recv_task = tokio::spawn(async move {
             while let Some(Ok(payload)) = reciever.next().await {
                 if matches!(&payload, Message::Close(_)) {
                     return;
                 }
-                if let Ok(message) = payload.into_text() {
-                    let _ = channel_send.send(ChatMessage {
-                        user: player.to_owned(),
-                        message,
-                        timestamp: 0,
+                if let Ok(text) = payload.into_text() {
+                    let command = from_str::<ChatCommand>(&text).unwrap_or_else(|_| {
+                        ChatCommand::Message(from_str::<ChatPost>(&text).unwrap_or(ChatPost {
+                            message: text,
+                            nonce: None,
+                        }))
                     });
+                    let post = match command {
+                        ChatCommand::Message(post) => post,
+                        ChatCommand::Typing => {
+                            let _ = app_state.chat_typing(&id, &player);
+                            continue;
+                        }
+                        ChatCommand::React { message_id, emoji } => {
+                            if let Err(reason) = app_state.chat_react(&id, &player, message_id, emoji) {
+                                let _ = direct_send.send(ChatReply::Rejected { nonce: None, reason }).await;
+                            }
+                            continue;
+                        }
+                    };
+                    let reply = match app_state.chat_post(&id, &player, &post.message, post.nonce.clone()) {
+                        Ok(message) => ChatReply::Ack {
+                            id: message.id,
+                            nonce: message.nonce,
+                            timestamp: message.timestamp,
+                        },
+                        Err(reason) => ChatReply::Rejected {
+                            nonce: post.nonce,
+                            reason,
+                        },
+                    };
+                    let _ = direct_send.send(reply).await;
+                }
+            }
+        });
+
+        tokio::select! {
+            _tx_s = (&mut send_task) => {
+                recv_task.abort();
+            },
+            _tx_r = (&mut recv_task) => {
+                send_task.abort();
+            }
+        }
+    })
+}
+
+async fn direct_inbox(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Conversation>>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.direct_inbox(&user.email).into())
+}
+
+/// Reading a conversation marks it as read.
+async fn direct_conversation(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    Query(query): Query<ChatHistoryQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<DirectMessageView>>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.direct_conversation(&user.email, &id, query.before)?.into())
+}
+
+async fn direct_send(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<ChatPost>,
+) -> Result<Json<DirectMessageView>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if user.username == "GUEST" {
+        return Err(StateError::Unauthorized);
+    }
+    Ok(app_state.direct_send(&user.email, &id, &payload.message)?.into())
+}
+
+async fn direct_events(cookies: Cookies, ws: WebSocketUpgrade, State(app_state): State<Arc<AppState>>) -> Response {
+    let user = match app_state.get_session(cookies.get(TOKEN)) {
+        Ok(user) => user,
+        Err(error) => return error.into_response(),
+    };
+    if user.username == "GUEST" {
+        return StateError::Unauthorized.into_response();
+    }
+    let player = user.email;
+
+    ws.on_upgrade(|socket: WebSocket| async move {
+        let _presence = Presence::connect(&app_state.presence, &player, Activity::Online);
+        let mut channel_recv = app_state.direct_relay.lock().unwrap().subscribe(&player);
+        let send_state = Arc::clone(&app_state);
+        let (mut sender, mut reciever) = socket.split();
+        let (error_send, mut error_recv) = tokio::sync::mpsc::channel::<StateError>(16);
+
+        let mut send_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    message = channel_recv.recv() => {
+                        let message = match message {
+                            Ok(message) => message,
+                            // skipped messages stay in the conversation store
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => return,
+                        };
+                        if let Ok(json_msg) = to_string(&send_state.direct_view(message)) {
+                            let _ = sender.send(json_msg.into()).await;
+                        }
+                    },
+                    Some(error) = error_recv.recv() => {
+                        if let Ok(json_msg) = to_string(&error) {
+                            let _ = sender.send(json_msg.into()).await;
+                        }
+                    }
+                }
+            }
+        });
+        let mut recv_task = tokio::spawn(async move {
+            while let Some(Ok(payload)) = reciever.next().await {
+                if matches!(&payload, Message::Close(_)) {
+                    return;
+                }
+                if let Ok(post) = payload.into_text().map(|text| from_str::<DirectPost>(&text)) {
+                    let result = post
+                        .map_err(|_| StateError::UnsupportedDataType("Not a direct message".into()))
+                        .and_then(|post| app_state.direct_send(&player, &post.to, &post.message));
+                    if let Err(error) = result {
+                        let _ = error_send.send(error).await;
+                    }
                 }
             }
         });
@@ -233,60 +828,354 @@ async fn join_chat(
     })
 }
 
+async fn chat_report(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<ReportCreate>,
+) -> Result<Json<ChatReport>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    Ok(app_state.chat_report(&id, &user.email, payload)?.into())
+}
+
+fn get_admin(app_state: &AppState, cookies: &Cookies) -> Result<String, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if !app_state.moderation.lock().unwrap().is_admin(&user.email) {
+        return Err(StateError::Unauthorized);
+    }
+    Ok(user.email)
+}
+
+async fn admin_reports(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ChatReport>>, StateError> {
+    get_admin(&app_state, &cookies)?;
+    Ok(app_state.moderation.lock().unwrap().reports().into())
+}
+
+async fn admin_report_dismiss(
+    cookies: Cookies,
+    Path(report_id): Path<u64>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    get_admin(&app_state, &cookies)?;
+    app_state.moderation.lock().unwrap().dismiss_report(report_id);
+    Ok(StatusCode::OK)
+}
+
+async fn admin_mute(
+    cookies: Cookies,
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<MuteCreate>,
+) -> Result<StatusCode, StateError> {
+    get_admin(&app_state, &cookies)?;
+    let until = chrono::Utc::now()
+        .timestamp()
+        .saturating_add(payload.minutes.max(1).saturating_mul(60));
+    app_state.moderation.lock().unwrap().mute(&payload.user, until);
+    Ok(StatusCode::OK)
+}
+
+async fn admin_unmute(
+    cookies: Cookies,
+    Path(email): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<StatusCode, StateError> {
+    get_admin(&app_state, &cookies)?;
+    app_state.moderation.lock().unwrap().unmute(&email);
+    Ok(StatusCode::OK)
+}
+
+async fn chat_history(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    Query(query): Query<ChatHistoryQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ChatMessage>>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if !app_state.chat_access(&id).can_read(&user.email) {
+        return Err(StateError::Unauthorized);
+    }
+    Ok(app_state.chat_history.lock().unwrap().before(&id, query.before).into())
+}
+
+async fn quoridor_replay(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<MatchReplay, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    app_state.quoridor_replay(&id, &user.email)
+}
+
+async fn quoridor_state(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    Query(query): Query<SnapshotQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<GameSnapshot>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if !app_state.quoridor_can_watch(&id, &user.email) {
+        return Err(StateError::Unauthorized);
+    }
+    let (game, _) = app_state.quoridor_get_full(&id).ok_or(StateError::NotFound)?;
+    let snapshot = game.read().unwrap().clone();
+    Ok(GameSnapshot::new(snapshot, query.hints).into())
+}
+
+async fn quoridor_legal_moves(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<LegalMoves>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if !app_state.quoridor_can_watch(&id, &user.email) {
+        return Err(StateError::Unauthorized);
+    }
+    let (game, _) = app_state.quoridor_get_full(&id).ok_or(StateError::NotFound)?;
+    let legal_moves = game.read().unwrap().legal_moves();
+    Ok(legal_moves.into())
+}
+
+/// Same path as a websocket move, subscribers get the usual events.
+async fn quoridor_http_move(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(player_move): Json<PlayerMove>,
+) -> Result<Json<MoveResponse>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    let (game, _) = app_state.quoridor_get_full(&id).ok_or(StateError::NotFound)?;
+    if !game.read().unwrap().contains_player(&user.email) {
+        return Err(StateError::Unauthorized);
+    }
+    match app_state.quoridor_make_move_with_state(&id, player_move, &user.email) {
+        (result, Some(game)) => Ok(MoveResponse { result, game }.into()),
+        (_, None) => Err(StateError::NotFound),
+    }
+}
+
+/// Long poll for bots, resolves once the match is past the given turn or finished.
+/// Answers with the current state when nothing happens within the timeout.
+async fn quoridor_wait(
+    cookies: Cookies,
+    Path(id): Path<String>,
+    Query(query): Query<WaitQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<GameSnapshot>, StateError> {
+    let user = app_state.get_session(cookies.get(TOKEN))?;
+    if !app_state.quoridor_can_watch(&id, &user.email) {
+        return Err(StateError::Unauthorized);
+    }
+    let (game, channel) = app_state.quoridor_get_full(&id).ok_or(StateError::NotFound)?;
+    let mut updates = channel.subscribe();
+    let deadline = tokio::time::Instant::now() + LONG_POLL_TIMEOUT;
+    loop {
+        let snapshot = game.read().unwrap().clone();
+        if snapshot.turns() > query.after || snapshot.is_finished() {
+            return Ok(GameSnapshot::new(snapshot, query.hints).into());
+        }
+        if !matches!(
+            tokio::time::timeout_at(deadline, game_update(&mut updates)).await,
+            Ok(Some(_))
+        ) {
+            return Ok(GameSnapshot::new(snapshot, query.hints).into());
+        }
+    }
+}
+
+/// Answers for the socket that sent a frame, they never go through the match broadcast.
+enum GameReply {
+    Welcome {
+        last_seq: Option<u64>,
+    },
+    Frame(Box<GameServerFrame>),
+    /// A legacy client sent a refused move, it gets the current snapshot to undo its local guess.
+    Resync,
+}
+
 async fn quoridor_game(
     cookies: Cookies,
     ws: WebSocketUpgrade,
     Path(id): Path<String>,
+    Query(query): Query<SnapshotQuery>,
     State(app_state): State<Arc<AppState>>,
 ) -> Response {
+    let hints = query.hints;
     let user_context = match app_state.get_session(cookies.get(TOKEN)) {
         Ok(user_context) => user_context,
         Err(err) => return err.into_response(),
     };
     let email = user_context.email.to_owned();
-    ws.on_upgrade(|mut socket: WebSocket| async move {
+    if !app_state.quoridor_can_watch(&id, &email) {
+        return StateError::Unauthorized.into_response();
+    }
+    ws.on_upgrade(move |mut socket: WebSocket| async move {
         let (game, channel_send) = match app_state.quoridor_get_full(&id) {
             Some(payload) => payload,
             None => return,
         };
-        let game_snapshot = to_string(&game.read().unwrap().clone());
+        let activity = if game.read().unwrap().contains_player(&email) {
+            Activity::Playing {
+                match_id: id.to_owned(),
+            }
+        } else {
+            Activity::Online
+        };
+        let is_player = matches!(activity, Activity::Playing { .. });
+        let presence = Presence::connect(&app_state.presence, &email, activity);
+        if is_player {
+            app_state.quoridor_player_connection(&id, &email, true);
+            app_state.chat_narrate(&id, SystemEvent::Joined { user: email.to_owned() });
+        }
+        let game_snapshot = to_string(&GameSnapshot::new(game.read().unwrap().clone(), hints));
         if let Ok(msg) = game_snapshot {
             let _ = socket.send(msg.into()).await;
         }
 
         let mut channel_recv = channel_send.subscribe();
+        let sender_channel = channel_send.clone();
         let (mut sender, mut reciever) = socket.split();
         let sender_game = Arc::clone(&game);
+        let recv_game = Arc::clone(&game);
+        let recv_state = Arc::clone(&app_state);
+        let recv_id = id.to_owned();
+        let leave_state = Arc::clone(&app_state);
+        let leaving = email.to_owned();
+        let (reply_send, mut reply_recv) = tokio::sync::mpsc::channel::<GameReply>(16);
 
         let mut send_task = tokio::spawn(async move {
-            while let Ok(msg) = channel_recv.recv().await {
-                let game_snapshot = sender_game.read().unwrap().clone();
-                if let Ok(snapshot) = to_string(&game_snapshot) {
-                    let _ = sender.send(snapshot.into()).await;
-                    if game_snapshot.winner.is_some() && game_snapshot.contains_player(&user_context.email) {
-                        app_state
-                            .leaderboard
-                            .lock()
-                            .unwrap()
-                            .process_game(&user_context, &game_snapshot);
-                    }
-                    if matches!(msg, PlayerMoveResult::GameFinished) {
-                        return;
+            // sockets stay on the legacy snapshot protocol until they say hello
+            let mut versioned = false;
+            // events up to this sequence already reached the client through a snapshot or a resume
+            let mut last_seq = 0;
+            loop {
+                tokio::select! {
+                    update = game_update(&mut channel_recv) => {
+                        let game_snapshot = sender_game.read().unwrap().clone();
+                        let frames: Vec<String> = match update {
+                            None => return,
+                            Some(GameUpdate::Events(events)) if versioned => events
+                                .into_iter()
+                                .filter_map(|event| {
+                                    if event.seq <= last_seq {
+                                        return None;
+                                    }
+                                    last_seq = event.seq;
+                                    to_string(&GameServerFrame::Event(event)).ok()
+                                })
+                                .collect(),
+                            Some(GameUpdate::Lagged) if versioned => {
+                                last_seq = game_snapshot.seq;
+                                let snapshot = GameSnapshot::new(game_snapshot.clone(), hints);
+                                to_string(&GameServerFrame::Snapshot(snapshot)).into_iter().collect()
+                            }
+                            Some(_) => to_string(&GameSnapshot::new(game_snapshot.clone(), hints)).into_iter().collect(),
+                        };
+                        for frame in frames {
+                            let _ = sender.send(frame.into()).await;
+                        }
+                        let unlocked = sender_game.write().unwrap().take_unlocked(&user_context.email);
+                        for achievement in unlocked {
+                            let notification = GameNotification::AchievementUnlocked(achievement);
+                            let notification = if versioned {
+                                to_string(&GameServerFrame::Notification(notification))
+                            } else {
+                                to_string(&notification)
+                            };
+                            if let Ok(notification) = notification {
+                                let _ = sender.send(notification.into()).await;
+                            }
+                        }
+                    },
+                    Some(reply) = reply_recv.recv() => {
+                        let frame = match reply {
+                            GameReply::Welcome { last_seq: seen } => {
+                                versioned = true;
+                                let game = sender_game.read().unwrap().clone();
+                                last_seq = game.seq;
+                                match seen.and_then(|seen| sender_channel.missed(seen, game.seq)) {
+                                    Some(events) => to_string(&GameServerFrame::Resumed {
+                                        version: PROTOCOL_VERSION,
+                                        events,
+                                    }),
+                                    None => to_string(&GameServerFrame::Welcome {
+                                        version: PROTOCOL_VERSION,
+                                        game: GameSnapshot::new(game, hints),
+                                    }),
+                                }
+                            }
+                            GameReply::Frame(frame) => to_string(&*frame),
+                            GameReply::Resync => {
+                                to_string(&GameSnapshot::new(sender_game.read().unwrap().clone(), hints))
+                            }
+                        };
+                        if let Ok(frame) = frame {
+                            let _ = sender.send(frame.into()).await;
+                        }
                     }
                 }
             }
         });
 
         let mut recv_task = tokio::spawn(async move {
+            let mut versioned = false;
             while let Some(Ok(msg)) = reciever.next().await {
                 if matches!(&msg, Message::Close(_)) {
                     return;
                 }
-                if let Ok(msg) = msg.into_text() {
-                    if let Ok(player_move) = from_str::<PlayerMove>(&msg) {
-                        let move_result = game.write().unwrap().make_move(player_move, &email);
-                        let _ = channel_send.send(move_result);
+                let msg = match msg.into_text() {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                let was_finished = recv_game.read().unwrap().is_finished();
+                let reply = if let Ok(frame) = from_str::<GameClientFrame>(&msg) {
+                    match frame {
+                        GameClientFrame::Hello { version, last_seq } if version == PROTOCOL_VERSION => {
+                            versioned = true;
+                            Some(GameReply::Welcome { last_seq })
+                        }
+                        GameClientFrame::Hello { .. } => Some(GameReply::Frame(Box::new(GameServerFrame::Error {
+                            request_id: None,
+                            error: GameError::UnsupportedVersion {
+                                supported: PROTOCOL_VERSION,
+                            },
+                        }))),
+                        GameClientFrame::Move {
+                            request_id,
+                            player_move,
+                        } => {
+                            let result = recv_state.quoridor_make_move(&recv_id, player_move, &email);
+                            Some(GameReply::Frame(Box::new(GameServerFrame::reply(
+                                request_id,
+                                result,
+                                was_finished,
+                            ))))
+                        }
+                        GameClientFrame::Request { request_id, request } => {
+                            let result = recv_state.quoridor_match_request(&recv_id, request, &email);
+                            Some(GameReply::Frame(Box::new(GameServerFrame::reply(
+                                request_id,
+                                result,
+                                was_finished,
+                            ))))
+                        }
                     }
+                } else if let Ok(player_move) = from_str::<PlayerMove>(&msg) {
+                    let result = recv_state.quoridor_make_move(&recv_id, player_move, &email);
+                    matches!(result, PlayerMoveResult::Disallowed(_)).then_some(GameReply::Resync)
+                } else if let Ok(request) = from_str::<MatchRequest>(&msg) {
+                    let result = recv_state.quoridor_match_request(&recv_id, request, &email);
+                    matches!(result, PlayerMoveResult::Disallowed(_)).then_some(GameReply::Resync)
+                } else {
+                    versioned.then_some(GameReply::Frame(Box::new(GameServerFrame::Error {
+                        request_id: None,
+                        error: GameError::Malformed,
+                    })))
+                };
+                if let Some(reply) = reply {
+                    let _ = reply_send.send(reply).await;
                 }
             }
         });
@@ -299,6 +1188,19 @@ async fn quoridor_game(
                 send_task.abort();
             }
         }
+        drop(presence);
+        if is_player {
+            leave_state.chat_narrate(
+                &id,
+                SystemEvent::Left {
+                    user: leaving.to_owned(),
+                },
+            );
+            // other tabs of the same player keep the game connected
+            if !leave_state.presence.lock().unwrap().is_in_match(&leaving, &id) {
+                leave_state.quoridor_player_connection(&id, &leaving, false);
+            }
+        }
     })
 }
 
@@ -315,6 +1217,7 @@ async fn main() {
 
     let state = AppState::new_as_arc();
     let state_for_thread = state.clone();
+    let state_for_matchmaking = state.clone();
 
     tokio::task::spawn(async move {
         loop {
@@ -323,22 +1226,67 @@ async fn main() {
         }
     });
 
+    tokio::task::spawn(async move {
+        loop {
+            state_for_matchmaking.matchmaking_pair();
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+        }
+    });
+
     let app = Router::new()
         .nest_service("/", ServeDir::new("static/build"))
         .route("/leaderboard", get(leaderboard))
+        .route("/leaderboard/cpu", get(cpu_leaderboard))
         .route("/auth/login", post(login))
         .route("/auth/guest_login", post(login_guest))
         .route("/auth/context", get(auth_context))
         .route("/auth/stats", get(get_personal_stats))
         .route("/auth/logout", delete(logout))
         .route("/auth/register", post(create_user))
+        .route("/users/privacy", post(user_privacy))
+        .route("/users/:id", get(user_profile))
+        .route("/social", get(social_overview))
+        .route(
+            "/social/friends/:id",
+            post(social_friend_add).delete(social_friend_remove),
+        )
+        .route("/social/friends/:id/accept", post(social_friend_accept))
+        .route("/social/friends/:id/decline", post(social_friend_decline))
+        .route("/social/follows/:id", post(social_follow).delete(social_unfollow))
+        .route("/social/blocks/:id", post(social_block).delete(social_unblock))
+        .route("/social/mutes/:id", post(social_mute).delete(social_unmute))
         .route("/chat/:id", get(join_chat))
+        .route("/chat/:id/history", get(chat_history))
+        .route("/chat/:id/report", post(chat_report))
+        .route("/dm", get(direct_inbox))
+        .route("/dm/events", get(direct_events))
+        .route("/dm/:id", get(direct_conversation).post(direct_send))
+        .route("/admin/reports", get(admin_reports))
+        .route("/admin/reports/:id", delete(admin_report_dismiss))
+        .route("/admin/mutes", post(admin_mute))
+        .route("/admin/mutes/:email", delete(admin_unmute))
+        .route("/lobby/events", get(lobby_events))
         .route("/quoridor/que", get(quoridor_que_get))
         .route("/quoridor/que/join/:host_name", get(quoridor_que_join))
         .route("/quoridor/que/host", get(quoridor_que_host))
+        .route("/quoridor/matchmaking", get(quoridor_matchmaking))
         .route("/quoridor/matches", get(quoridor_get_matches))
+        .route("/quoridor/my-matches", get(quoridor_my_matches))
+        .route("/tournaments", get(tournament_list).post(tournament_create))
+        .route("/tournaments/:id", get(tournament_get))
+        .route("/tournaments/:id/register", post(tournament_register))
+        .route("/tournaments/:id/start", post(tournament_start))
+        .route("/tournaments/:id/events", get(tournament_events))
+        .route("/challenges", get(challenge_pending).post(challenge_create))
+        .route("/challenges/:code", get(challenge_get).delete(challenge_cancel))
+        .route("/challenges/:code/accept", post(challenge_accept))
         .route("/quoridor/solo", get(quoridor_cpu))
         .route("/quoridor/events/:id", get(quoridor_game))
+        .route("/quoridor/:id/replay", get(quoridor_replay))
+        .route("/quoridor/:id/state", get(quoridor_state))
+        .route("/quoridor/:id/move", post(quoridor_http_move))
+        .route("/quoridor/:id/wait", get(quoridor_wait))
+        .route("/quoridor/:id/legal-moves", get(quoridor_legal_moves))
         .with_state(state)
         .layer(CookieManagerLayer::new());
 
@@ -347,3 +1295,190 @@ async fn main() {
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chat::spectator_channel;
+    use std::sync::OnceLock;
+
+    /// The databases open relative paths and only once per process, so the tests share a state in a temp dir.
+    fn app_state() -> Arc<AppState> {
+        static STATE: OnceLock<Arc<AppState>> = OnceLock::new();
+        STATE
+            .get_or_init(|| {
+                let dir = std::env::temp_dir().join(format!("corridor-api-test-{}", std::process::id()));
+                std::fs::create_dir_all(&dir).unwrap();
+                std::env::set_current_dir(&dir).unwrap();
+                AppState::new_as_arc()
+            })
+            .clone()
+    }
+
+    fn cookies_of(app_state: &AppState, name: &str) -> Cookies {
+        let user = app_state.user_guest_session(name.to_owned()).unwrap();
+        let cookies = Cookies::default();
+        cookies.add(Cookie::new(TOKEN, user.auth_token));
+        cookies
+    }
+
+    /// Starts a match between two fresh guests, the one to move comes first.
+    fn new_match(app_state: &AppState, prefix: &str) -> (String, Cookies, Cookies) {
+        let players = [format!("{prefix}1"), format!("{prefix}2")];
+        let first = cookies_of(app_state, &players[0]);
+        let second = cookies_of(app_state, &players[1]);
+        let id = app_state.quoridor_new_game(&players).unwrap();
+        let (game, _) = app_state.quoridor_get_full(&id).unwrap();
+        let current = game.read().unwrap().legal_moves().player;
+        match current == players[0] {
+            true => (id, first, second),
+            false => (id, second, first),
+        }
+    }
+
+    fn first_pawn_move(app_state: &AppState, id: &str) -> PlayerMove {
+        let (game, _) = app_state.quoridor_get_full(id).unwrap();
+        let (row, col) = game.read().unwrap().legal_moves().pawn[0];
+        PlayerMove::QuoridorMove { row, col }
+    }
+
+    #[tokio::test]
+    async fn state_endpoint_returns_the_match() {
+        let app_state = app_state();
+        let (id, cookies, _) = new_match(&app_state, "state");
+        let query = Query(SnapshotQuery { hints: true });
+        let snapshot = quoridor_state(cookies, Path(id), query, State(app_state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(snapshot.game.turns(), 0);
+        assert!(snapshot.legal_moves.is_some());
+        let anonymous = quoridor_state(
+            Cookies::default(),
+            Path("missing".to_owned()),
+            Query(SnapshotQuery::default()),
+            State(app_state),
+        );
+        assert!(matches!(anonymous.await, Err(StateError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn private_games_are_hidden_from_outsiders() {
+        let app_state = app_state();
+        let players = ["private1".to_owned(), "private2".to_owned()];
+        let player = cookies_of(&app_state, &players[0]);
+        let outsider = cookies_of(&app_state, "private-outsider");
+        let settings = MatchSettings {
+            private: true,
+            ..MatchSettings::default()
+        };
+        let id = app_state.quoridor_new_game_with_settings(&players, settings).unwrap();
+        let watched = quoridor_state(
+            outsider,
+            Path(id.to_owned()),
+            Query(SnapshotQuery::default()),
+            State(app_state.clone()),
+        );
+        assert!(matches!(watched.await, Err(StateError::Unauthorized)));
+        let played = quoridor_state(
+            player,
+            Path(id),
+            Query(SnapshotQuery::default()),
+            State(app_state.clone()),
+        );
+        assert!(played.await.is_ok());
+        assert_eq!(app_state.quoridor_get_public_id_by_player(&players[0]), None);
+        assert!(app_state.quoridor_get_id_by_player(&players[0]).is_some());
+    }
+
+    #[tokio::test]
+    async fn rematch_keeps_the_chat_history() {
+        let app_state = app_state();
+        let (id, current, _) = new_match(&app_state, "rematch");
+        let (game, _) = app_state.quoridor_get_full(&id).unwrap();
+        let (first, second) = {
+            let game = game.read().unwrap();
+            (game.up_player.to_owned(), game.down_player.to_owned())
+        };
+        app_state.chat_post(&id, &first, "good luck", None).unwrap();
+        let spectator = cookies_of(&app_state, "rematch-spectator");
+        let watcher = app_state.get_session(spectator.get(TOKEN)).unwrap().email;
+        app_state
+            .chat_post(&spectator_channel(&id), &watcher, "go!", None)
+            .unwrap();
+        app_state.quoridor_make_move(&id, PlayerMove::Concede, &first);
+        app_state.quoridor_match_request(&id, MatchRequest::OfferRematch, &first);
+        app_state.quoridor_match_request(&id, MatchRequest::AcceptRematch, &second);
+        let rematch = game.read().unwrap().rematch.clone().unwrap();
+
+        let query = || Query(ChatHistoryQuery { before: None });
+        let players = chat_history(current, Path(rematch.to_owned()), query(), State(app_state.clone()));
+        let players = players.await.unwrap();
+        assert_eq!(
+            players
+                .iter()
+                .map(|message| message.message.as_str())
+                .collect::<Vec<_>>(),
+            ["good luck"]
+        );
+        let spectators = chat_history(
+            spectator,
+            Path(spectator_channel(&rematch)),
+            query(),
+            State(app_state.clone()),
+        );
+        assert_eq!(spectators.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn move_endpoint_is_for_players_only() {
+        let app_state = app_state();
+        let (id, current, waiting) = new_match(&app_state, "move");
+        let player_move = first_pawn_move(&app_state, &id);
+        let outsider = cookies_of(&app_state, "move-outsider");
+        let refused = quoridor_http_move(
+            outsider,
+            Path(id.to_owned()),
+            State(app_state.clone()),
+            Json(player_move.clone()),
+        );
+        assert!(matches!(refused.await, Err(StateError::Unauthorized)));
+
+        let (game, _) = app_state.quoridor_get_full(&id).unwrap();
+        let stamp = chrono::Utc::now().timestamp() - 10;
+        game.write().unwrap().set_timestamp(stamp);
+        let early = quoridor_http_move(
+            waiting,
+            Path(id.to_owned()),
+            State(app_state.clone()),
+            Json(player_move.clone()),
+        );
+        let early = early.await.unwrap();
+        assert!(matches!(early.result, PlayerMoveResult::Disallowed(_)));
+        assert_eq!(early.game.get_timestamp(), stamp);
+
+        let moved = quoridor_http_move(current, Path(id), State(app_state), Json(player_move));
+        let moved = moved.await.unwrap();
+        assert!(matches!(moved.result, PlayerMoveResult::Ok));
+        assert_eq!(moved.game.turns(), 1);
+    }
+
+    #[tokio::test]
+    async fn wait_endpoint_resolves_on_the_next_turn() {
+        let app_state = app_state();
+        let (id, current, waiting) = new_match(&app_state, "wait");
+        let player_move = first_pawn_move(&app_state, &id);
+        let query = Query(WaitQuery { after: 0, hints: false });
+        let wait = tokio::spawn(quoridor_wait(
+            waiting,
+            Path(id.to_owned()),
+            query,
+            State(app_state.clone()),
+        ));
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        assert!(!wait.is_finished());
+        let moved = quoridor_http_move(current, Path(id), State(app_state), Json(player_move));
+        assert!(matches!(moved.await.unwrap().result, PlayerMoveResult::Ok));
+        let snapshot = wait.await.unwrap().unwrap();
+        assert_eq!(snapshot.game.turns(), 1);
+    }
+}
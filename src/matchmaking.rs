@@ -0,0 +1,129 @@
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::errors::StateError;
+
+const BASE_RATING_WINDOW: i64 = 50;
+const RATING_WINDOW_GROWTH: i64 = 10; // per second in queue
+const MAX_RATING_WINDOW: i64 = 800;
+
+pub struct QueueEntry {
+    pub player: String,
+    pub rating: i32,
+    pub joined: i64,
+    /// Kept open after a match id is sent, the entry may be queued again if the pairing falls through.
+    pub sender: UnboundedSender<String>,
+}
+
+#[derive(Default)]
+pub struct MatchmakingQueue {
+    entries: Vec<QueueEntry>,
+}
+
+impl MatchmakingQueue {
+    pub fn join(&mut self, entry: QueueEntry) -> Result<(), StateError> {
+        if self.contains(&entry.player) {
+            return Err(StateError::AlreadyTaken);
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn leave(&mut self, player: &str) {
+        self.entries.retain(|entry| entry.player != player);
+    }
+
+    pub fn contains(&self, player: &str) -> bool {
+        self.entries.iter().any(|entry| entry.player == player)
+    }
+
+    /// Removes every pair that fits in both players' rating windows, longest waiting players first.
+    /// `can_pair` lets the caller veto pairs, e.g. players who blocked each other.
+    pub fn take_pairs(&mut self, now: i64, can_pair: impl Fn(&str, &str) -> bool) -> Vec<(QueueEntry, QueueEntry)> {
+        self.entries.sort_by_key(|entry| entry.joined);
+        let mut pairs = Vec::new();
+        let mut idx = 0;
+        while idx < self.entries.len() {
+            let window = rating_window(self.entries[idx].joined, now);
+            let best = self
+                .entries
+                .iter()
+                .enumerate()
+                .skip(idx + 1)
+                .filter(|(_, other)| {
+                    let diff = (self.entries[idx].rating - other.rating).abs() as i64;
+                    diff <= window.min(rating_window(other.joined, now))
+                        && can_pair(&self.entries[idx].player, &other.player)
+                })
+                .min_by_key(|(_, other)| (self.entries[idx].rating - other.rating).abs())
+                .map(|(other_idx, _)| other_idx);
+            match best {
+                Some(other_idx) => {
+                    let other = self.entries.remove(other_idx);
+                    let entry = self.entries.remove(idx);
+                    pairs.push((entry, other));
+                }
+                None => idx += 1,
+            }
+        }
+        pairs
+    }
+}
+
+fn rating_window(joined: i64, now: i64) -> i64 {
+    (BASE_RATING_WINDOW + (now - joined).max(0) * RATING_WINDOW_GROWTH).min(MAX_RATING_WINDOW)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(player: &str, rating: i32, joined: i64) -> QueueEntry {
+        QueueEntry {
+            player: player.to_owned(),
+            rating,
+            joined,
+            sender: tokio::sync::mpsc::unbounded_channel().0,
+        }
+    }
+
+    #[test]
+    fn rejects_double_join() {
+        let mut que = MatchmakingQueue::default();
+        assert!(que.join(entry("pl1", 1200, 0)).is_ok());
+        assert!(que.join(entry("pl1", 1200, 0)).is_err());
+    }
+
+    #[test]
+    fn pairs_closest_ratings() {
+        let mut que = MatchmakingQueue::default();
+        que.join(entry("pl1", 1200, 0)).unwrap();
+        que.join(entry("pl2", 1240, 0)).unwrap();
+        que.join(entry("pl3", 1210, 0)).unwrap();
+        let pairs = que.take_pairs(0, |_, _| true);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0.player.as_str(), pairs[0].1.player.as_str()), ("pl1", "pl3"));
+        assert!(que.contains("pl2"));
+    }
+
+    #[test]
+    fn window_widens_over_time() {
+        let mut que = MatchmakingQueue::default();
+        que.join(entry("pl1", 1200, 0)).unwrap();
+        que.join(entry("pl2", 1500, 0)).unwrap();
+        assert!(que.take_pairs(5, |_, _| true).is_empty());
+        let pairs = que.take_pairs(30, |_, _| true);
+        assert_eq!(pairs.len(), 1);
+        assert!(!que.contains("pl1") && !que.contains("pl2"));
+    }
+
+    #[test]
+    fn skips_vetoed_pairs() {
+        let mut que = MatchmakingQueue::default();
+        que.join(entry("pl1", 1200, 0)).unwrap();
+        que.join(entry("pl2", 1200, 0)).unwrap();
+        assert!(que
+            .take_pairs(0, |first, second| (first, second) != ("pl1", "pl2"))
+            .is_empty());
+        assert!(que.contains("pl1") && que.contains("pl2"));
+    }
+}
@@ -3,9 +3,13 @@ use axum::response::IntoResponse;
 use axum::Json;
 use serde::{Deserialize, Serialize};
 
+use crate::achievements::Achievement;
 use crate::errors::StateError;
-use crate::leaderboard::UserLeaderBoard;
-use crate::quoridor::QuoridorMatch;
+use crate::leaderboard::{MatchSummary, UserCpuRecord, UserLeaderBoard};
+use crate::moderation::ChatRejection;
+use crate::presence::Activity;
+use crate::quoridor::cpu::CpuDifficulty;
+use crate::quoridor::{LegalMoves, MatchOffers, MatchResult, MatchSettings, MoveError, QuoridorMatch, Side};
 
 impl IntoResponse for UserLeaderBoard {
     fn into_response(self) -> axum::response::Response {
@@ -13,6 +17,20 @@ impl IntoResponse for UserLeaderBoard {
     }
 }
 
+#[derive(Serialize)]
+pub struct PersonalStats {
+    #[serde(flatten)]
+    pub record: UserLeaderBoard,
+    pub achievements: Vec<Achievement>,
+    pub cpu: Vec<UserCpuRecord>,
+}
+
+impl IntoResponse for PersonalStats {
+    fn into_response(self) -> axum::response::Response {
+        Json(self).into_response()
+    }
+}
+
 impl IntoResponse for StateError {
     fn into_response(self) -> axum::response::Response {
         let mut status_code = None;
@@ -25,6 +43,7 @@ impl IntoResponse for StateError {
             }
             Self::AlreadyTaken => {}
             Self::UnsupportedDataType(_) => {}
+            Self::Rejected(_) => {}
             Self::ServerError => {
                 status_code.replace(StatusCode::INTERNAL_SERVER_ERROR);
             }
@@ -57,7 +76,9 @@ pub struct UserContext {
     pub email: String,
     pub username: String,
     pub auth_token: String,
+    pub user_id: Option<String>,
     pub active_match: Option<String>,
+    pub active_matches: Vec<String>,
 }
 
 impl IntoResponse for UserContext {
@@ -66,11 +87,192 @@ impl IntoResponse for UserContext {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PrivacySettings {
+    #[serde(default)]
+    pub hide_online_status: bool,
+    #[serde(default)]
+    pub hide_match_history: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicProfile {
+    pub id: String,
+    pub username: String,
+    pub joined: i64,
+    pub rating: i32,
+    pub wins: i32,
+    pub loses: i32,
+    pub draws: i32,
+    pub achievements: Vec<Achievement>,
+    pub recent_matches: Option<Vec<MatchSummary>>,
+    pub live_match: Option<String>,
+    pub online: Option<bool>,
+}
+
+impl IntoResponse for PublicProfile {
+    fn into_response(self) -> axum::response::Response {
+        Json(self).into_response()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    /// Public user id, emails are never shown to other users.
+    pub id: String,
+    pub username: String,
+    pub activity: Option<Activity>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SocialOverview {
+    pub friends: Vec<Contact>,
+    pub incoming: Vec<Contact>,
+    pub outgoing: Vec<Contact>,
+    pub following: Vec<Contact>,
+    pub blocked: Vec<Contact>,
+    pub muted: Vec<Contact>,
+}
+
+impl IntoResponse for SocialOverview {
+    fn into_response(self) -> axum::response::Response {
+        Json(self).into_response()
+    }
+}
+
+/// Picks the CPU for solo games and the CPU challenge table.
+#[derive(Deserialize)]
+pub struct CpuQuery {
+    #[serde(default)]
+    pub difficulty: CpuDifficulty,
+}
+
+#[derive(Deserialize)]
+pub struct ChatHistoryQuery {
+    pub before: Option<u64>,
+}
+
+/// Options of the chat socket. Clients that do not opt into `frames` get bare `ChatMessage`s and no replies.
+#[derive(Deserialize, Default)]
+pub struct ChatSocketQuery {
+    #[serde(default)]
+    pub frames: bool,
+}
+
+#[derive(Serialize)]
+pub struct MatchReplay {
+    pub id: String,
+    pub game: QuoridorMatch,
+    pub chat: Vec<ChatMessage>,
+}
+
+impl IntoResponse for MatchReplay {
+    fn into_response(self) -> axum::response::Response {
+        Json(self).into_response()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChatMessage {
+    #[serde(default)]
+    pub id: u64,
     pub user: String,
     pub message: String,
     pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+/// Chat frame from a client, plain text frames are accepted as a message without a nonce.
+#[derive(Deserialize)]
+pub struct ChatPost {
+    pub message: String,
+    pub nonce: Option<String>,
+}
+
+/// Tagged chat frame from a client. Frames that do not parse fall back to `ChatPost`, then to plain text.
+#[derive(Deserialize)]
+pub enum ChatCommand {
+    Message(ChatPost),
+    Typing,
+    React { message_id: u64, emoji: String },
+}
+
+/// Everything the chat socket broadcasts. System events are narrated by the server and not stored.
+#[derive(Serialize, Debug, Clone)]
+pub enum ChatFrame {
+    Message(ChatMessage),
+    System {
+        event: SystemEvent,
+        timestamp: i64,
+    },
+    Typing {
+        user: String,
+    },
+    Reaction {
+        user: String,
+        message_id: u64,
+        emoji: String,
+    },
+    /// The subscriber fell behind and skipped frames, history can be fetched over HTTP.
+    Missed {
+        count: u64,
+    },
+}
+
+impl ChatFrame {
+    pub fn system(event: SystemEvent) -> Self {
+        ChatFrame::System {
+            event,
+            timestamp: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// User behind the frame, used to hide muted and blocked users.
+    pub fn author(&self) -> Option<&str> {
+        match self {
+            ChatFrame::Message(message) => Some(&message.user),
+            ChatFrame::Typing { user } | ChatFrame::Reaction { user, .. } => Some(user),
+            ChatFrame::System { .. } | ChatFrame::Missed { .. } => None,
+        }
+    }
+
+    /// Serializes the frame for a socket, older clients only understand bare messages.
+    pub fn encode(&self, frames: bool) -> Option<String> {
+        match self {
+            _ if frames => serde_json::to_string(self).ok(),
+            ChatFrame::Message(message) => serde_json::to_string(message).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum SystemEvent {
+    Joined { user: String },
+    Left { user: String },
+    Moved { user: String, player_move: PlayerMove },
+    Conceded { user: String },
+    DrawOffered { user: String },
+    GameFinished { result: MatchResult },
+}
+
+/// Sent only to the author of a message, once it is stored or when it is refused.
+#[derive(Serialize, Debug)]
+pub enum ChatReply {
+    Ack {
+        id: u64,
+        nonce: Option<String>,
+        timestamp: i64,
+    },
+    Rejected {
+        nonce: Option<String>,
+        reason: ChatRejection,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -81,14 +283,265 @@ pub enum PlayerMove {
     QuoridorMove { row: usize, col: usize },
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum MatchRequest {
+    OfferDraw,
+    AcceptDraw,
+    DeclineDraw,
+    RequestTakeback,
+    AcceptTakeback,
+    DeclineTakeback,
+    OfferRematch,
+    AcceptRematch,
+    DeclineRematch,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum PlayerMoveResult {
     Ok,
-    Disallowed,
+    Disallowed(MoveError),
+    GameFinished,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub enum GameNotification {
+    AchievementUnlocked(Achievement),
+}
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Client frames of the versioned game protocol, opened with `Hello`.
+/// Bare `PlayerMove` and `MatchRequest` frames are still understood by sockets that never said hello.
+#[derive(Debug, Deserialize)]
+pub enum GameClientFrame {
+    /// Reconnecting clients pass the last sequence number they saw to receive what they missed.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        last_seq: Option<u64>,
+    },
+    Move {
+        request_id: u64,
+        player_move: PlayerMove,
+    },
+    Request {
+        request_id: u64,
+        request: MatchRequest,
+    },
+}
+
+/// Acks and errors only reach the socket that sent the request, events reach every subscriber.
+#[derive(Debug, Serialize)]
+pub enum GameServerFrame {
+    Welcome {
+        version: u32,
+        game: GameSnapshot,
+    },
+    /// Missed events for a reconnecting client, sent instead of the welcome snapshot.
+    Resumed {
+        version: u32,
+        events: Vec<SequencedEvent>,
+    },
+    Ack {
+        request_id: u64,
+    },
+    Error {
+        request_id: Option<u64>,
+        error: GameError,
+    },
+    Event(SequencedEvent),
+    /// Sent instead of the skipped events when a subscriber falls behind.
+    Snapshot(GameSnapshot),
+    Notification(GameNotification),
+}
+
+impl GameServerFrame {
+    /// A finished result only counts as an error when the game had already ended before the request.
+    pub fn reply(request_id: u64, result: PlayerMoveResult, was_finished: bool) -> Self {
+        let error = match result {
+            PlayerMoveResult::Ok => return GameServerFrame::Ack { request_id },
+            PlayerMoveResult::GameFinished if !was_finished => return GameServerFrame::Ack { request_id },
+            PlayerMoveResult::GameFinished => GameError::GameFinished,
+            PlayerMoveResult::Disallowed(reason) => GameError::Disallowed(reason),
+        };
+        GameServerFrame::Error {
+            request_id: Some(request_id),
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub enum GameError {
+    UnsupportedVersion { supported: u32 },
+    Malformed,
+    Disallowed(MoveError),
     GameFinished,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Copy)]
+pub enum WallOrientation {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub enum GameEvent {
+    MoveMade {
+        player: String,
+        row: usize,
+        col: usize,
+    },
+    WallPlaced {
+        player: String,
+        orientation: WallOrientation,
+        row: usize,
+        col: usize,
+    },
+    /// Carries the restored match, replaying a takeback from events alone is not possible.
+    TakenBack {
+        player: String,
+        plies: usize,
+        game: Box<QuoridorMatch>,
+    },
+    OffersChanged(MatchOffers),
+    TurnChanged {
+        player: String,
+        turn: usize,
+    },
+    GameOver {
+        result: MatchResult,
+    },
+    PlayerDisconnected {
+        player: String,
+        grace_until: i64,
+    },
+    PlayerReconnected {
+        player: String,
+    },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    pub event: GameEvent,
+}
+
+/// Answer of `POST /quoridor/:id/move`, the match as it is right after the move.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveResponse {
+    pub result: PlayerMoveResult,
+    pub game: QuoridorMatch,
+}
+
+#[derive(Deserialize)]
+pub struct WaitQuery {
+    pub after: usize,
+    #[serde(default)]
+    pub hints: bool,
+}
+
+#[derive(Deserialize, Default)]
+pub struct SnapshotQuery {
+    #[serde(default)]
+    pub hints: bool,
+}
+
+/// Match snapshot, with the legal moves of the side to move when the client asked for hints.
+#[derive(Debug, Serialize)]
+pub struct GameSnapshot {
+    #[serde(flatten)]
+    pub game: QuoridorMatch,
+    /// Derived from `result`, older clients only know this field.
+    pub winner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub legal_moves: Option<LegalMoves>,
+}
+
+impl GameSnapshot {
+    pub fn new(game: QuoridorMatch, hints: bool) -> Self {
+        Self {
+            winner: match &game.result {
+                Some(MatchResult::Win { winner, .. }) => Some(winner.to_owned()),
+                _ => None,
+            },
+            legal_moves: hints.then(|| game.legal_moves()),
+            game,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserMatch {
+    pub id: String,
+    pub opponent: String,
+    pub your_turn: bool,
+    pub turn: usize,
+    pub days_per_move: Option<u32>,
+    pub deadline: i64,
+}
+
+/// Options a host publishes with `/quoridor/que/host`, passed as query parameters.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HostOptions {
+    #[serde(default)]
+    pub casual: bool,
+    pub side: Option<Side>,
+    /// The only time control a host can pick: days per move for a correspondence game.
+    /// Without it the game is realtime and uses the fixed AFK move timer.
+    pub days_per_move: Option<u32>,
+    pub walls: Option<usize>,
+    pub move_limit: Option<usize>,
+    pub min_rating: Option<i32>,
+    pub max_rating: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueHost {
+    pub host: String,
+    pub host_rating: i32,
+    pub settings: MatchSettings,
+    pub min_rating: Option<i32>,
+    pub max_rating: Option<i32>,
+}
+
+impl QueueHost {
+    pub fn new(host: &str, host_rating: i32, options: HostOptions) -> Self {
+        Self {
+            host: host.to_owned(),
+            host_rating,
+            settings: MatchSettings {
+                host_side: options.side,
+                days_per_move: options.days_per_move,
+                move_limit: options.move_limit,
+                casual: options.casual,
+                walls: options.walls,
+                ..MatchSettings::default()
+            },
+            min_rating: options.min_rating,
+            max_rating: options.max_rating,
+        }
+    }
+
+    pub fn accepts(&self, rating: i32) -> bool {
+        self.min_rating.is_none_or(|min| rating >= min) && self.max_rating.is_none_or(|max| rating <= max)
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub enum LobbyEvent {
+    HostAdded(QueueHost),
+    HostRemoved { host: String },
+    MatchStarted(QuoridorMatchMeta),
+    MatchFinished { id: String, result: MatchResult },
+    OnlinePlayers(usize),
+}
+
+#[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct QuoridorMatchMeta {
     id: String,
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+
+use crate::messages::ChatMessage;
+
+pub const MAX_MESSAGE_LEN: usize = 500;
+pub const MAX_REACTION_LEN: usize = 8;
+const FLOOD_WINDOW: i64 = 10;
+const FLOOD_LIMIT: usize = 5;
+const BANNED_WORDS_VAR: &str = "CHAT_BANNED_WORDS";
+const ADMINS_VAR: &str = "ADMINS";
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChatRejection {
+    ReadOnly,
+    TooLong { max: usize },
+    Flooding { retry_after: i64 },
+    Muted { until: i64 },
+    UnknownMessage,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportCreate {
+    pub message_id: u64,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatReport {
+    pub id: u64,
+    pub channel: String,
+    pub reporter: String,
+    pub reason: String,
+    pub message: ChatMessage,
+    /// Messages sent right before the reported one.
+    pub context: Vec<ChatMessage>,
+    pub created: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MuteCreate {
+    pub user: String,
+    pub minutes: i64,
+}
+
+/// Word filter and admin list come from comma separated env variables, mutes and reports live in sled.
+pub struct Moderation {
+    banned_words: Vec<String>,
+    admins: Vec<String>,
+    recent_posts: HashMap<String, Vec<i64>>,
+    mutes: sled::Tree,
+    reports: sled::Tree,
+    db: sled::Db,
+}
+
+impl Default for Moderation {
+    fn default() -> Self {
+        let db = sled::open("moderation").expect("Unable to start DB!");
+        Self {
+            banned_words: env_list(BANNED_WORDS_VAR)
+                .into_iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+            admins: env_list(ADMINS_VAR),
+            recent_posts: HashMap::new(),
+            mutes: db.open_tree("mutes").expect("Unable to start DB!"),
+            reports: db.open_tree("reports").expect("Unable to start DB!"),
+            db,
+        }
+    }
+}
+
+impl Moderation {
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.iter().any(|admin| admin == user)
+    }
+
+    /// Returns the text with banned words masked, or the reason the post is refused.
+    /// Accepted posts count against the flood limit.
+    pub fn check_post(&mut self, user: &str, text: &str, now: i64) -> Result<String, ChatRejection> {
+        if text.chars().count() > MAX_MESSAGE_LEN {
+            return Err(ChatRejection::TooLong { max: MAX_MESSAGE_LEN });
+        }
+        if let Some(until) = self.muted_until(user, now) {
+            return Err(ChatRejection::Muted { until });
+        }
+        let posts = self.recent_posts.entry(user.to_owned()).or_default();
+        posts.retain(|posted| *posted > now - FLOOD_WINDOW);
+        if posts.len() >= FLOOD_LIMIT {
+            return Err(ChatRejection::Flooding {
+                retry_after: posts[0] + FLOOD_WINDOW - now,
+            });
+        }
+        posts.push(now);
+        Ok(self.filter(text))
+    }
+
+    /// Masks banned words, the whitespace between words is kept as it was.
+    pub fn filter(&self, text: &str) -> String {
+        text.split_inclusive(char::is_whitespace)
+            .map(|piece| {
+                let word = piece.trim_end_matches(char::is_whitespace);
+                let bare = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+                if !bare.is_empty() && self.banned_words.contains(&bare) {
+                    format!("{}{}", "*".repeat(word.chars().count()), &piece[word.len()..])
+                } else {
+                    piece.to_owned()
+                }
+            })
+            .collect()
+    }
+
+    pub fn mute(&self, user: &str, until: i64) {
+        let _ = self.mutes.insert(user, &until.to_be_bytes());
+    }
+
+    pub fn unmute(&self, user: &str) {
+        let _ = self.mutes.remove(user);
+    }
+
+    pub fn muted_until(&self, user: &str, now: i64) -> Option<i64> {
+        let until = self
+            .mutes
+            .get(user)
+            .ok()
+            .flatten()
+            .and_then(|value| value.as_ref().try_into().ok())
+            .map(i64::from_be_bytes)?;
+        if until > now {
+            Some(until)
+        } else {
+            self.unmute(user);
+            None
+        }
+    }
+
+    pub fn report(&self, mut report: ChatReport) -> ChatReport {
+        report.id = self.db.generate_id().unwrap_or_default();
+        if let Ok(value) = to_vec(&report) {
+            let _ = self.reports.insert(report.id.to_be_bytes(), value);
+        }
+        report
+    }
+
+    pub fn reports(&self) -> Vec<ChatReport> {
+        self.reports
+            .iter()
+            .values()
+            .flatten()
+            .filter_map(|value| from_slice::<ChatReport>(&value).ok())
+            .collect()
+    }
+
+    pub fn dismiss_report(&self, id: u64) {
+        let _ = self.reports.remove(id.to_be_bytes());
+    }
+
+    /// Forgets flood counters of users who have been quiet for the whole window.
+    pub fn clean_up(&mut self, now: i64) {
+        self.recent_posts
+            .retain(|_, posts| posts.iter().any(|posted| *posted > now - FLOOD_WINDOW));
+    }
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn moderation() -> Moderation {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Moderation {
+            banned_words: vec!["darn".to_owned()],
+            admins: Vec::new(),
+            recent_posts: HashMap::new(),
+            mutes: db.open_tree("mutes").unwrap(),
+            reports: db.open_tree("reports").unwrap(),
+            db,
+        }
+    }
+
+    #[test]
+    fn masks_banned_words() {
+        assert_eq!(moderation().filter("well Darn! that"), "well ***** that");
+        assert_eq!(moderation().filter("darn\ttab\n\ndarn,"), "****\ttab\n\n*****");
+    }
+
+    #[test]
+    fn limits_flood_and_length() {
+        let mut moderation = moderation();
+        for _ in 0..FLOOD_LIMIT {
+            assert!(moderation.check_post("pl1", "hi", 0).is_ok());
+        }
+        assert!(matches!(
+            moderation.check_post("pl1", "hi", 1),
+            Err(ChatRejection::Flooding { retry_after: 9 })
+        ));
+        assert!(moderation.check_post("pl1", "hi", FLOOD_WINDOW).is_ok());
+        let long = "a".repeat(MAX_MESSAGE_LEN + 1);
+        assert!(matches!(
+            moderation.check_post("pl2", &long, 0),
+            Err(ChatRejection::TooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn mutes_expire() {
+        let mut moderation = moderation();
+        moderation.mute("pl1", 100);
+        assert_eq!(
+            moderation.check_post("pl1", "hi", 50),
+            Err(ChatRejection::Muted { until: 100 })
+        );
+        assert!(moderation.check_post("pl1", "hi", 100).is_ok());
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Activity {
+    Online,
+    InQueue,
+    Playing { match_id: String },
+}
+
+impl Activity {
+    fn priority(&self) -> u8 {
+        match self {
+            Activity::Online => 0,
+            Activity::InQueue => 1,
+            Activity::Playing { .. } => 2,
+        }
+    }
+}
+
+/// Open websocket connections per user, a user is online while at least one of them is alive.
+#[derive(Default)]
+pub struct Presence {
+    connections: HashMap<String, Vec<(u64, Activity)>>,
+    next_id: u64,
+}
+
+impl Presence {
+    /// The returned guard keeps the connection registered until it is dropped with the socket.
+    pub fn connect(presence: &Arc<Mutex<Presence>>, user: &str, activity: Activity) -> PresenceGuard {
+        let mut inner = presence.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner
+            .connections
+            .entry(user.to_owned())
+            .or_default()
+            .push((id, activity));
+        PresenceGuard {
+            presence: Arc::clone(presence),
+            user: user.to_owned(),
+            id,
+        }
+    }
+
+    pub fn is_online(&self, user: &str) -> bool {
+        self.connections.contains_key(user)
+    }
+
+    pub fn online_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// The most engaged activity wins, playing beats queueing beats idling in the lobby.
+    pub fn activity(&self, user: &str) -> Option<Activity> {
+        self.connections.get(user).and_then(|connections| {
+            connections
+                .iter()
+                .map(|(_, activity)| activity)
+                .max_by_key(|activity| activity.priority())
+                .cloned()
+        })
+    }
+
+    pub fn is_in_match(&self, user: &str, match_id: &str) -> bool {
+        self.connections.get(user).is_some_and(|connections| {
+            connections
+                .iter()
+                .any(|(_, activity)| matches!(activity, Activity::Playing { match_id: id } if id == match_id))
+        })
+    }
+
+    fn disconnect(&mut self, user: &str, id: u64) {
+        if let Some(connections) = self.connections.get_mut(user) {
+            connections.retain(|(connection, _)| *connection != id);
+            if connections.is_empty() {
+                self.connections.remove(user);
+            }
+        }
+    }
+}
+
+pub struct PresenceGuard {
+    presence: Arc<Mutex<Presence>>,
+    user: String,
+    id: u64,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        if let Ok(mut presence) = self.presence.lock() {
+            presence.disconnect(&self.user, self.id);
+        }
+    }
+}
@@ -1,17 +1,43 @@
 use super::*;
 pub const CPU: &str = "|QCPU|";
 
+/// Strength of the CPU opponent, `Hard` is the original CPU.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CpuDifficulty {
+    /// Races to the goal and never places a wall.
+    Easy,
+    /// Considers walls on about half of its turns.
+    Normal,
+    #[default]
+    Hard,
+}
+
+impl CpuDifficulty {
+    pub const ALL: [CpuDifficulty; 3] = [CpuDifficulty::Easy, CpuDifficulty::Normal, CpuDifficulty::Hard];
+}
+
 pub struct CpuPlayer {
     game: Quoridor,
     cpu_path: Vec<(usize, usize)>,
     player_path: Vec<(usize, usize)>,
 }
 
+/// Turns a move found on the mirrored board back into one for the real board.
+pub fn mirror_move(player_move: PlayerMove) -> PlayerMove {
+    match player_move {
+        PlayerMove::QuoridorMove { row, col } => PlayerMove::QuoridorMove { row: 8 - row, col },
+        PlayerMove::QuoridorWallH { row, col } => PlayerMove::QuoridorWallH { row: 7 - row, col },
+        PlayerMove::QuoridorWallV { row, col } => PlayerMove::QuoridorWallV { row: 7 - row, col },
+        PlayerMove::Concede => PlayerMove::Concede,
+    }
+}
+
 impl CpuPlayer {
-    pub fn get_cpu_move(game: &Quoridor, only_palyer_moves_allowed: bool) -> PlayerMove {
+    pub fn get_cpu_move(game: &Quoridor, only_palyer_moves_allowed: bool, difficulty: CpuDifficulty) -> PlayerMove {
         let mut instance = Self::new(game.clone());
         let new_position = instance.cpu_path[instance.cpu_path.len() - 2];
         if !only_palyer_moves_allowed
+            && instance.considers_walls(difficulty)
             && !instance.is_cpu_closer(new_position)
             && instance.game.down_player_free_walls != 0
         {
@@ -25,6 +51,15 @@ impl CpuPlayer {
         }
     }
 
+    fn considers_walls(&self, difficulty: CpuDifficulty) -> bool {
+        use rand::Rng;
+        match difficulty {
+            CpuDifficulty::Easy => false,
+            CpuDifficulty::Normal => rand::thread_rng().gen_bool(0.5),
+            CpuDifficulty::Hard => true,
+        }
+    }
+
     fn is_cpu_closer(&self, position: (usize, usize)) -> bool {
         self.is_cpu_closer_or_rng() && !self.can_enemy_jump_over_cpu(position)
             || self.can_cpu_jump_over(position)
@@ -76,11 +111,7 @@ impl CpuPlayer {
     fn get_difference_between_total_positions(&self, position_x: (usize, usize), position_y: (usize, usize)) -> usize {
         let x = position_x.0 + position_x.1;
         let y = position_y.0 + position_y.1;
-        if x > y {
-            x - y
-        } else {
-            y - x
-        }
+        x.abs_diff(y)
     }
 
     fn get_best_wall(&mut self) -> Option<PlayerMove> {
@@ -128,7 +159,7 @@ impl CpuPlayer {
     }
 
     fn add_new_hwall_path_result(&mut self, position: (usize, usize), storage: &mut Vec<(usize, (usize, usize))>) {
-        if self.game.new_h_wall(position) {
+        if self.game.new_h_wall(position).is_ok() {
             storage.push((
                 self.game.get_shortest_path(self.game.up_player, 8).unwrap().len(),
                 position,
@@ -137,7 +168,7 @@ impl CpuPlayer {
         }
     }
     fn add_new_vwall_path_result(&mut self, position: (usize, usize), storage: &mut Vec<(usize, (usize, usize))>) {
-        if self.game.new_v_wall(position) {
+        if self.game.new_v_wall(position).is_ok() {
             storage.push((
                 self.game.get_shortest_path(self.game.up_player, 8).unwrap().len(),
                 position,
@@ -1,6 +1,29 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Clone)]
+pub const WALLS_PER_PLAYER: usize = 9;
+
+/// Why a move or match request was refused.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MoveError {
+    NotYourTurn,
+    NotAPlayer,
+    NoWallsLeft,
+    WallOutOfBounds,
+    WallOverlaps,
+    WallCrosses,
+    WallBlocksPath,
+    PawnOutOfBounds,
+    PawnNotAdjacent,
+    PawnBlockedByWall,
+    WallsDisallowedWhilePawnsOverlap,
+    /// Answering a draw, takeback or rematch offer the opponent did not make.
+    NoPendingOffer,
+    /// The request does not apply to this match, like a draw against the CPU or a takeback before any move.
+    NotAvailable,
+    UnknownMatch,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Quoridor {
     pub up_player: (usize, usize),
     pub down_player: (usize, usize),
@@ -15,13 +38,26 @@ impl Quoridor {
         Self {
             up_player: (0, 4),
             down_player: (8, 4),
-            up_player_free_walls: 9,
-            down_player_free_walls: 9,
+            up_player_free_walls: WALLS_PER_PLAYER,
+            down_player_free_walls: WALLS_PER_PLAYER,
             vertical_walls: Vec::new(),
             horizontal_walls: Vec::new(),
         }
     }
 
+    /// The board turned upside down, the up player becomes the down player and the other way around.
+    pub fn mirrored(&self) -> Self {
+        let flip_walls = |walls: &Vec<(usize, usize)>| walls.iter().map(|(row, col)| (7 - row, *col)).collect();
+        Self {
+            up_player: (8 - self.down_player.0, self.down_player.1),
+            down_player: (8 - self.up_player.0, self.up_player.1),
+            up_player_free_walls: self.down_player_free_walls,
+            down_player_free_walls: self.up_player_free_walls,
+            vertical_walls: flip_walls(&self.vertical_walls),
+            horizontal_walls: flip_walls(&self.horizontal_walls),
+        }
+    }
+
     pub fn get_shortest_path(&self, player: (usize, usize), target: usize) -> Option<Vec<(usize, usize)>> {
         a_star_traitbased::AStar::run(self, player, (Some(target), None))
     }
@@ -30,113 +66,150 @@ impl Quoridor {
         self.get_shortest_path(start_position, target).is_some()
     }
 
-    pub fn try_moving_up_player(&mut self, new_position: (usize, usize)) -> bool {
-        if self.is_move_blocked_by_wall_or_wrong(self.up_player, new_position) {
-            return false;
-        }
+    pub fn try_moving_up_player(&mut self, new_position: (usize, usize)) -> Result<(), MoveError> {
+        self.check_step(self.up_player, new_position)?;
         self.up_player = new_position;
-        true
+        Ok(())
     }
 
-    pub fn try_moving_down_player(&mut self, new_position: (usize, usize)) -> bool {
-        if self.is_move_blocked_by_wall_or_wrong(self.down_player, new_position) {
-            return false;
-        }
+    pub fn try_moving_down_player(&mut self, new_position: (usize, usize)) -> Result<(), MoveError> {
+        self.check_step(self.down_player, new_position)?;
         self.down_player = new_position;
-        true
+        Ok(())
     }
 
-    pub fn new_h_wall(&mut self, wall: (usize, usize)) -> bool {
+    pub fn new_h_wall(&mut self, wall: (usize, usize)) -> Result<(), MoveError> {
         if wall.0 > 7 || wall.1 > 7 {
-            return false;
-        }
-        if !self.wall_h_is_possible(wall) {
-            return false;
+            return Err(MoveError::WallOutOfBounds);
         }
+        self.check_h_wall(wall)?;
         self.horizontal_walls.push(wall);
         if self.player_can_win(self.up_player, 8) && self.player_can_win(self.down_player, 0) {
-            return true;
+            return Ok(());
         }
         self.horizontal_walls.pop();
-        false
+        Err(MoveError::WallBlocksPath)
     }
 
-    fn wall_h_is_possible(&self, new_wall: (usize, usize)) -> bool {
+    fn check_h_wall(&self, new_wall: (usize, usize)) -> Result<(), MoveError> {
         for wall in &self.horizontal_walls {
             if *wall == new_wall {
-                return false;
+                return Err(MoveError::WallOverlaps);
             }
             if wall.1 <= 6 && (wall.0, wall.1 + 1) == new_wall {
-                return false;
+                return Err(MoveError::WallOverlaps);
             }
             if wall.1 >= 1 && (wall.0, wall.1 - 1) == new_wall {
-                return false;
+                return Err(MoveError::WallOverlaps);
             }
         }
-        !self.vertical_walls.contains(&new_wall)
+        if self.vertical_walls.contains(&new_wall) {
+            return Err(MoveError::WallCrosses);
+        }
+        Ok(())
     }
 
-    pub fn new_v_wall(&mut self, wall: (usize, usize)) -> bool {
+    pub fn new_v_wall(&mut self, wall: (usize, usize)) -> Result<(), MoveError> {
         if wall.0 > 7 || wall.1 > 7 {
-            return false;
-        }
-        if !self.wall_v_is_possible(wall) {
-            return false;
+            return Err(MoveError::WallOutOfBounds);
         }
+        self.check_v_wall(wall)?;
         self.vertical_walls.push(wall);
         if self.player_can_win(self.up_player, 8) && self.player_can_win(self.down_player, 0) {
-            return true;
+            return Ok(());
         }
         self.vertical_walls.pop();
-        false
+        Err(MoveError::WallBlocksPath)
     }
 
-    fn wall_v_is_possible(&self, new_wall: (usize, usize)) -> bool {
+    fn check_v_wall(&self, new_wall: (usize, usize)) -> Result<(), MoveError> {
         for wall in &self.vertical_walls {
             if *wall == new_wall {
-                return false;
+                return Err(MoveError::WallOverlaps);
             }
             if wall.0 <= 6 && (wall.0 + 1, wall.1) == new_wall {
-                return false;
+                return Err(MoveError::WallOverlaps);
             }
             if wall.0 >= 1 && (wall.0 - 1, wall.1) == new_wall {
-                return false;
+                return Err(MoveError::WallOverlaps);
             }
         }
-        !self.horizontal_walls.contains(&new_wall)
+        if self.horizontal_walls.contains(&new_wall) {
+            return Err(MoveError::WallCrosses);
+        }
+        Ok(())
+    }
+
+    /// Squares a pawn can step to, the same ones `try_moving_up_player`/`try_moving_down_player` accept.
+    pub fn legal_pawn_moves(&self, from_position: (usize, usize)) -> Vec<(usize, usize)> {
+        self.build_possible_paths(from_position)
+    }
+
+    /// Horizontal walls that fit next to the existing ones and leave both players a way to their goal.
+    pub fn legal_h_walls(&self) -> Vec<(usize, usize)> {
+        let mut probe = self.clone();
+        Self::wall_slots()
+            .filter(|wall| self.check_h_wall(*wall).is_ok())
+            .filter(|wall| {
+                probe.horizontal_walls.push(*wall);
+                let legal = probe.player_can_win(probe.up_player, 8) && probe.player_can_win(probe.down_player, 0);
+                probe.horizontal_walls.pop();
+                legal
+            })
+            .collect()
     }
 
-    fn is_move_blocked_by_wall_or_wrong(&self, start_position: (usize, usize), possible_path: (usize, usize)) -> bool {
+    pub fn legal_v_walls(&self) -> Vec<(usize, usize)> {
+        let mut probe = self.clone();
+        Self::wall_slots()
+            .filter(|wall| self.check_v_wall(*wall).is_ok())
+            .filter(|wall| {
+                probe.vertical_walls.push(*wall);
+                let legal = probe.player_can_win(probe.up_player, 8) && probe.player_can_win(probe.down_player, 0);
+                probe.vertical_walls.pop();
+                legal
+            })
+            .collect()
+    }
+
+    fn wall_slots() -> impl Iterator<Item = (usize, usize)> {
+        (0..=7).flat_map(|row| (0..=7).map(move |col| (row, col)))
+    }
+
+    fn check_step(&self, start_position: (usize, usize), possible_path: (usize, usize)) -> Result<(), MoveError> {
+        if possible_path.0 > 8 || possible_path.1 > 8 {
+            return Err(MoveError::PawnOutOfBounds);
+        }
         if start_position.0 == possible_path.0 {
             let column_move = Self::sort_positions(possible_path.1, start_position.1);
             if column_move.1 - column_move.0 != 1 {
-                return true;
+                return Err(MoveError::PawnNotAdjacent);
             };
             for wall in &self.vertical_walls {
                 if wall.0 == start_position.0 && wall.1 == column_move.0 {
-                    return true;
+                    return Err(MoveError::PawnBlockedByWall);
                 }
                 if start_position.0 != 0 && wall.0 == start_position.0 - 1 && wall.1 == column_move.0 {
-                    return true;
+                    return Err(MoveError::PawnBlockedByWall);
                 }
             }
-            return false;
+            return Ok(());
         } else if start_position.1 == possible_path.1 {
             let row_move = Self::sort_positions(possible_path.0, start_position.0);
             if row_move.1 - row_move.0 != 1 {
-                return true;
+                return Err(MoveError::PawnNotAdjacent);
             };
             for wall in &self.horizontal_walls {
                 if wall.1 == start_position.1 && wall.0 == row_move.0 {
-                    return true;
+                    return Err(MoveError::PawnBlockedByWall);
                 }
                 if start_position.1 != 0 && wall.1 == start_position.1 - 1 && wall.0 == row_move.0 {
-                    return true;
+                    return Err(MoveError::PawnBlockedByWall);
                 }
             }
-            return false;
+            return Ok(());
         }
-        true
+        Err(MoveError::PawnNotAdjacent)
     }
 
     fn sort_positions(x: usize, y: usize) -> (usize, usize) {
@@ -150,25 +223,25 @@ impl Quoridor {
         let mut possible_paths = vec![];
         if from_position.0 > 0 {
             let new_position = (from_position.0 - 1, from_position.1);
-            if !self.is_move_blocked_by_wall_or_wrong(from_position, new_position) {
+            if self.check_step(from_position, new_position).is_ok() {
                 possible_paths.push(new_position);
             }
         }
         if from_position.1 < 8 {
             let new_position = (from_position.0, from_position.1 + 1);
-            if !self.is_move_blocked_by_wall_or_wrong(from_position, new_position) {
+            if self.check_step(from_position, new_position).is_ok() {
                 possible_paths.push(new_position);
             }
         }
         if from_position.0 < 8 {
             let new_position = (from_position.0 + 1, from_position.1);
-            if !self.is_move_blocked_by_wall_or_wrong(from_position, new_position) {
+            if self.check_step(from_position, new_position).is_ok() {
                 possible_paths.push(new_position);
             }
         }
         if from_position.1 > 0 {
             let new_position = (from_position.0, from_position.1 - 1);
-            if !self.is_move_blocked_by_wall_or_wrong(from_position, new_position) {
+            if self.check_step(from_position, new_position).is_ok() {
                 possible_paths.push(new_position);
             }
         }
@@ -1,150 +1,780 @@
 extern crate a_star_traitbased;
-use crate::messages::{PlayerMove, PlayerMoveResult};
+use crate::achievements::Achievement;
+use crate::messages::{GameEvent, PlayerMove, PlayerMoveResult, WallOrientation};
 pub mod cpu;
 mod game;
 use game::Quoridor;
-use serde::Serialize;
+pub use game::{MoveError, WALLS_PER_PLAYER};
+use serde::{Deserialize, Serialize};
 
 const AFK_CC_TIMER: i64 = 180;
+const SECONDS_IN_DAY: i64 = 24 * 60 * 60;
+const REPETITION_LIMIT: usize = 3;
+const MAX_WALLS_PER_PLAYER: usize = 20;
+pub const DISCONNECT_GRACE: i64 = 60;
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum WinReason {
+    ReachedGoal,
+    Concede,
+    Timeout,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DrawReason {
+    Agreement,
+    Repetition,
+    MoveLimit,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum MatchResult {
+    Win { winner: String, reason: WinReason },
+    Draw { reason: DrawReason },
+}
+
+/// Result of a finished match from the point of view of one player.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Outcome {
+    /// Points for the result as used by Elo ratings.
+    pub fn score(self) -> f64 {
+        match self {
+            Outcome::Win => 1.0,
+            Outcome::Loss => 0.0,
+            Outcome::Draw => 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MatchSettings {
+    #[serde(default)]
+    pub host_side: Option<Side>,
+    /// Correspondence time control, realtime games fall back to the AFK timer.
+    #[serde(default)]
+    pub days_per_move: Option<u32>,
+    /// The game is drawn once this many turns are played without a winner.
+    #[serde(default)]
+    pub move_limit: Option<usize>,
+    /// Casual games do not change ratings, they still show up in the match history.
+    #[serde(default)]
+    pub casual: bool,
+    /// Walls each player starts with, the standard game uses `WALLS_PER_PLAYER`.
+    #[serde(default)]
+    pub walls: Option<usize>,
+    /// Strength of the CPU, ignored in games between humans.
+    #[serde(default)]
+    pub difficulty: cpu::CpuDifficulty,
+    /// Private games are never announced or listed and have no spectator chat.
+    #[serde(default)]
+    pub private: bool,
+}
+
+impl MatchSettings {
+    pub fn is_correspondence(&self) -> bool {
+        self.days_per_move.is_some()
+    }
+
+    pub fn walls_per_player(&self) -> usize {
+        self.walls
+            .map_or(WALLS_PER_PLAYER, |walls| walls.min(MAX_WALLS_PER_PLAYER))
+    }
+
+    pub fn move_time_limit(&self) -> i64 {
+        self.days_per_move
+            .map_or(AFK_CC_TIMER, |days| days as i64 * SECONDS_IN_DAY)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum HistoryEntry {
+    Move { player: String, player_move: PlayerMove },
+    Takeback { player: String, plies: usize },
+}
+
+/// Pending offers and the rematch link, announced together whenever one of them changes.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct MatchOffers {
+    pub draw_offer: Option<String>,
+    pub takeback_offer: Option<String>,
+    pub rematch_offer: Option<String>,
+    pub rematch: Option<String>,
+}
+
+/// Everything the side to move may play, empty once the game is over.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct LegalMoves {
+    pub player: String,
+    pub pawn: Vec<(usize, usize)>,
+    pub horizontal_walls: Vec<(usize, usize)>,
+    pub vertical_walls: Vec<(usize, usize)>,
+}
+
+/// A player who lost every game socket. Their clock is paused until `grace_until`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Disconnect {
+    pub player: String,
+    pub since: i64,
+    pub grace_until: i64,
+}
+
+/// Match state before a change, the difference to the current state is what gets broadcast.
+pub struct GameMark {
+    history: usize,
+    turn: usize,
+    offers: MatchOffers,
+    finished: bool,
+    disconnected: Vec<Disconnect>,
+}
+
+/// State before a ply, restored when the ply is taken back.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TurnSnapshot {
+    player: String,
+    game: Quoridor,
+    turn: usize,
+    current: String,
+    only_player_moves_allowed: bool,
+    positions: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct QuoridorMatch {
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     timestamp: i64,
     pub up_player: String,
     pub down_player: String,
     game: Quoridor,
     turn: usize,
     current: String,
-    pub winner: Option<String>,
+    pub result: Option<MatchResult>,
     only_player_moves_allowed: bool,
+    pub settings: MatchSettings,
+    pub draw_offer: Option<String>,
+    pub takeback_offer: Option<String>,
+    pub rematch_offer: Option<String>,
+    pub rematch: Option<String>,
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    /// Sequence number of the last event broadcast for this match.
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(default)]
+    pub disconnected: Vec<Disconnect>,
+    #[serde(skip_serializing, default)]
+    positions: Vec<String>,
+    #[serde(skip_serializing, default)]
+    snapshots: Vec<TurnSnapshot>,
+    /// Achievements unlocked by the result that no socket of the player has announced yet.
+    #[serde(skip)]
+    unlocked: Vec<(String, Achievement)>,
+}
+
+/// Repetition and takeback bookkeeping, clients never see it but stored matches need it to carry on.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MatchTracking {
+    positions: Vec<String>,
+    snapshots: Vec<TurnSnapshot>,
 }
 
 impl QuoridorMatch {
-    pub fn new(player_list: &Vec<String>) -> Self {
-        QuoridorMatch {
-            up_player: player_list[0].to_owned(),
+    /// The first player in the list is the host, the up player always moves first.
+    /// A lone player faces the CPU, a CPU host opens the game itself.
+    pub fn new(player_list: &[String], settings: MatchSettings) -> Self {
+        let host = player_list[0].to_owned();
+        let guest = if player_list.len() >= 2 {
+            player_list[1].to_owned()
+        } else {
+            cpu::CPU.to_owned()
+        };
+        let (up_player, down_player) = match settings.host_side {
+            Some(Side::Down) if guest != cpu::CPU => (guest, host),
+            _ => (host, guest),
+        };
+        let mut game = Quoridor::new();
+        game.up_player_free_walls = settings.walls_per_player();
+        game.down_player_free_walls = settings.walls_per_player();
+        let mut new_match = QuoridorMatch {
             timestamp: chrono::Utc::now().timestamp(),
-            down_player: if player_list.len() >= 2 {
-                player_list[1].to_owned()
-            } else {
-                cpu::CPU.to_owned()
-            },
-            game: Quoridor::new(),
+            current: up_player.to_owned(),
+            up_player,
+            down_player,
+            game,
             turn: 0,
-            current: player_list[0].to_owned(),
-            winner: None,
+            result: None,
             only_player_moves_allowed: false,
+            settings,
+            draw_offer: None,
+            takeback_offer: None,
+            rematch_offer: None,
+            rematch: None,
+            history: Vec::new(),
+            seq: 0,
+            disconnected: Vec::new(),
+            positions: Vec::new(),
+            snapshots: Vec::new(),
+            unlocked: Vec::new(),
+        };
+        new_match.record_position();
+        if new_match.current == cpu::CPU {
+            new_match.cpu_player_move();
         }
+        new_match
     }
 
     pub fn refresh_timestamp(&mut self) {
         self.set_timestamp(chrono::Utc::now().timestamp())
     }
 
+    /// A disconnected player inside the grace period does not lose on time.
     pub fn timeout_guard(&mut self) {
-        if self.get_timestamp() + AFK_CC_TIMER < chrono::Utc::now().timestamp() {
-            self.make_move(PlayerMove::Concede, &self.current.clone());
+        let now = chrono::Utc::now().timestamp();
+        let in_grace = self
+            .disconnected
+            .iter()
+            .any(|disconnect| disconnect.player == self.current && disconnect.grace_until > now);
+        if !self.is_finished() && !in_grace && self.deadline() < now {
+            self.set_winner(self.opponent_of(&self.current).to_owned(), WinReason::Timeout);
         }
     }
 
     pub fn make_move(&mut self, player_move: PlayerMove, player: &str) -> PlayerMoveResult {
-        if self.winner.is_some() {
+        if self.is_finished() {
             return PlayerMoveResult::GameFinished;
         }
-        self.refresh_timestamp();
-        let result = match player_move {
-            PlayerMove::QuoridorWallH { row, col } => self.new_h_wall(player, (row, col)),
-            PlayerMove::QuoridorWallV { row, col } => self.new_v_wall(player, (row, col)),
-            PlayerMove::QuoridorMove { row, col } => self.move_player(player, (row, col)),
+        let snapshot = self.snapshot(player);
+        let result = match &player_move {
+            PlayerMove::QuoridorWallH { row, col } => self.new_h_wall(player, (*row, *col)),
+            PlayerMove::QuoridorWallV { row, col } => self.new_v_wall(player, (*row, *col)),
+            PlayerMove::QuoridorMove { row, col } => self.move_player(player, (*row, *col)),
             PlayerMove::Concede => self.concede(player),
         };
+        // a refused move does not reset the clock
+        if !matches!(result, PlayerMoveResult::Disallowed(_)) {
+            self.refresh_timestamp();
+            self.history.push(HistoryEntry::Move {
+                player: player.to_owned(),
+                player_move,
+            });
+        }
         if matches!(result, PlayerMoveResult::Ok) {
+            self.snapshots.push(snapshot);
             self.end_turn();
         };
         result
     }
 
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some()
+    }
+
+    pub fn outcome_for(&self, player: &str) -> Option<Outcome> {
+        match &self.result {
+            Some(MatchResult::Win { winner, .. }) if winner == player => Some(Outcome::Win),
+            Some(MatchResult::Win { .. }) => Some(Outcome::Loss),
+            Some(MatchResult::Draw { .. }) => Some(Outcome::Draw),
+            None => None,
+        }
+    }
+
+    /// Finished games linger for a while so players can agree on a rematch.
+    pub fn is_expired(&self) -> bool {
+        self.is_finished() && self.get_timestamp() + AFK_CC_TIMER < chrono::Utc::now().timestamp()
+    }
+
+    pub fn add_unlocked(&mut self, player: &str, achievements: Vec<Achievement>) {
+        self.unlocked.extend(
+            achievements
+                .into_iter()
+                .map(|achievement| (player.to_owned(), achievement)),
+        );
+    }
+
+    /// Unlocked achievements of the player, handed out once no matter how many sockets see the result.
+    pub fn take_unlocked(&mut self, player: &str) -> Vec<Achievement> {
+        let (taken, kept) = self.unlocked.drain(..).partition(|(owner, _)| owner == player);
+        self.unlocked = kept;
+        taken.into_iter().map(|(_, achievement)| achievement).collect()
+    }
+
+    pub fn offer_draw(&mut self, player: &str) -> PlayerMoveResult {
+        if !self.contains_player(player) {
+            return PlayerMoveResult::Disallowed(MoveError::NotAPlayer);
+        }
+        if self.is_finished() || self.contains_player(cpu::CPU) {
+            return PlayerMoveResult::Disallowed(MoveError::NotAvailable);
+        }
+        self.draw_offer = Some(player.to_owned());
+        PlayerMoveResult::Ok
+    }
+
+    pub fn accept_draw(&mut self, player: &str) -> PlayerMoveResult {
+        if let Err(error) = self.check_answer(&self.draw_offer, player) {
+            return PlayerMoveResult::Disallowed(error);
+        }
+        if self.is_finished() {
+            return PlayerMoveResult::Disallowed(MoveError::NotAvailable);
+        }
+        self.draw_offer = None;
+        self.result = Some(MatchResult::Draw {
+            reason: DrawReason::Agreement,
+        });
+        PlayerMoveResult::GameFinished
+    }
+
+    pub fn decline_draw(&mut self, player: &str) -> PlayerMoveResult {
+        if let Err(error) = self.check_answer(&self.draw_offer, player) {
+            return PlayerMoveResult::Disallowed(error);
+        }
+        self.draw_offer = None;
+        PlayerMoveResult::Ok
+    }
+
+    /// CPU games grant the takeback right away, human opponents have to accept it.
+    pub fn request_takeback(&mut self, player: &str) -> PlayerMoveResult {
+        if !self.contains_player(player) {
+            return PlayerMoveResult::Disallowed(MoveError::NotAPlayer);
+        }
+        if self.is_finished() || self.takeback_plies(player).is_none() {
+            return PlayerMoveResult::Disallowed(MoveError::NotAvailable);
+        }
+        if self.contains_player(cpu::CPU) {
+            return self.apply_takeback(player);
+        }
+        self.takeback_offer = Some(player.to_owned());
+        PlayerMoveResult::Ok
+    }
+
+    pub fn accept_takeback(&mut self, player: &str) -> PlayerMoveResult {
+        if let Err(error) = self.check_answer(&self.takeback_offer, player) {
+            return PlayerMoveResult::Disallowed(error);
+        }
+        if self.is_finished() {
+            return PlayerMoveResult::Disallowed(MoveError::NotAvailable);
+        }
+        match self.takeback_offer.take() {
+            Some(offer) => self.apply_takeback(&offer),
+            None => PlayerMoveResult::Disallowed(MoveError::NoPendingOffer),
+        }
+    }
+
+    pub fn decline_takeback(&mut self, player: &str) -> PlayerMoveResult {
+        if let Err(error) = self.check_answer(&self.takeback_offer, player) {
+            return PlayerMoveResult::Disallowed(error);
+        }
+        self.takeback_offer = None;
+        PlayerMoveResult::Ok
+    }
+
+    pub fn offer_rematch(&mut self, player: &str) -> PlayerMoveResult {
+        if !self.contains_player(player) {
+            return PlayerMoveResult::Disallowed(MoveError::NotAPlayer);
+        }
+        if !self.is_finished() || self.rematch.is_some() {
+            return PlayerMoveResult::Disallowed(MoveError::NotAvailable);
+        }
+        self.refresh_timestamp();
+        self.rematch_offer = Some(player.to_owned());
+        PlayerMoveResult::Ok
+    }
+
+    pub fn decline_rematch(&mut self, player: &str) -> PlayerMoveResult {
+        if let Err(error) = self.check_answer(&self.rematch_offer, player) {
+            return PlayerMoveResult::Disallowed(error);
+        }
+        self.rematch_offer = None;
+        PlayerMoveResult::Ok
+    }
+
+    /// Only the opponent of whoever made the offer can answer it.
+    fn check_answer(&self, offer: &Option<String>, player: &str) -> Result<(), MoveError> {
+        if !self.contains_player(player) {
+            return Err(MoveError::NotAPlayer);
+        }
+        match offer {
+            Some(offer) if offer != player => Ok(()),
+            _ => Err(MoveError::NoPendingOffer),
+        }
+    }
+
+    /// Players for the rematch, the first mover alternates, against the CPU as well.
+    pub fn rematch_lobby(&self, player: &str) -> Option<Vec<String>> {
+        if self.rematch.is_some() || !self.contains_player(player) {
+            return None;
+        }
+        if self.down_player == cpu::CPU {
+            return Some(vec![cpu::CPU.to_owned(), self.up_player.to_owned()]);
+        }
+        if self.up_player == cpu::CPU {
+            return Some(vec![self.down_player.to_owned()]);
+        }
+        match &self.rematch_offer {
+            Some(offer) if offer != player => Some(vec![self.down_player.to_owned(), self.up_player.to_owned()]),
+            _ => None,
+        }
+    }
+
     pub fn contains_player(&self, player: &str) -> bool {
         self.up_player == player || self.down_player == player
     }
 
-    fn get_timestamp(&self) -> i64 {
+    pub fn opponent_of(&self, player: &str) -> &str {
+        if player == self.up_player {
+            &self.down_player
+        } else {
+            &self.up_player
+        }
+    }
+
+    pub fn free_walls(&self, player: &str) -> usize {
+        if player == self.up_player {
+            self.game.up_player_free_walls
+        } else {
+            self.game.down_player_free_walls
+        }
+    }
+
+    pub fn legal_moves(&self) -> LegalMoves {
+        if self.is_finished() {
+            return LegalMoves::default();
+        }
+        let position = if self.current == self.up_player {
+            self.game.up_player
+        } else {
+            self.game.down_player
+        };
+        let can_place_walls = !self.only_player_moves_allowed && self.free_walls(&self.current) > 0;
+        LegalMoves {
+            player: self.current.to_owned(),
+            pawn: self.game.legal_pawn_moves(position),
+            horizontal_walls: if can_place_walls {
+                self.game.legal_h_walls()
+            } else {
+                Vec::new()
+            },
+            vertical_walls: if can_place_walls {
+                self.game.legal_v_walls()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    pub fn player_disconnected(&mut self, player: &str, now: i64) {
+        if self.is_finished() || !self.contains_player(player) || self.is_disconnected(player) {
+            return;
+        }
+        self.disconnected.push(Disconnect {
+            player: player.to_owned(),
+            since: now,
+            grace_until: now + DISCONNECT_GRACE,
+        });
+    }
+
+    /// Time spent disconnected on their own turn, up to the grace period, is given back to the player.
+    pub fn player_reconnected(&mut self, player: &str, now: i64) {
+        let index = match self
+            .disconnected
+            .iter()
+            .position(|disconnect| disconnect.player == player)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let disconnect = self.disconnected.remove(index);
+        if self.current == player && !self.is_finished() {
+            let paused = now.min(disconnect.grace_until) - disconnect.since.max(self.timestamp);
+            if paused > 0 {
+                self.timestamp += paused;
+            }
+        }
+    }
+
+    pub fn is_disconnected(&self, player: &str) -> bool {
+        self.disconnected.iter().any(|disconnect| disconnect.player == player)
+    }
+
+    pub fn mark(&self) -> GameMark {
+        GameMark {
+            history: self.history.len(),
+            turn: self.turn,
+            offers: self.offers(),
+            finished: self.is_finished(),
+            disconnected: self.disconnected.clone(),
+        }
+    }
+
+    pub fn offers(&self) -> MatchOffers {
+        MatchOffers {
+            draw_offer: self.draw_offer.clone(),
+            takeback_offer: self.takeback_offer.clone(),
+            rematch_offer: self.rematch_offer.clone(),
+            rematch: self.rematch.clone(),
+        }
+    }
+
+    /// Typed events for everything that changed since the mark, CPU replies included.
+    pub fn events_since(&self, mark: &GameMark) -> Vec<GameEvent> {
+        let mut events: Vec<GameEvent> = self.history[mark.history.min(self.history.len())..]
+            .iter()
+            .filter_map(|entry| match entry {
+                HistoryEntry::Move { player, player_move } => match *player_move {
+                    PlayerMove::QuoridorMove { row, col } => Some(GameEvent::MoveMade {
+                        player: player.to_owned(),
+                        row,
+                        col,
+                    }),
+                    PlayerMove::QuoridorWallH { row, col } => Some(GameEvent::WallPlaced {
+                        player: player.to_owned(),
+                        orientation: WallOrientation::Horizontal,
+                        row,
+                        col,
+                    }),
+                    PlayerMove::QuoridorWallV { row, col } => Some(GameEvent::WallPlaced {
+                        player: player.to_owned(),
+                        orientation: WallOrientation::Vertical,
+                        row,
+                        col,
+                    }),
+                    PlayerMove::Concede => None,
+                },
+                HistoryEntry::Takeback { player, plies } => Some(GameEvent::TakenBack {
+                    player: player.to_owned(),
+                    plies: *plies,
+                    game: Box::new(self.clone()),
+                }),
+            })
+            .collect();
+        for disconnect in self
+            .disconnected
+            .iter()
+            .filter(|known| !mark.disconnected.contains(known))
+        {
+            events.push(GameEvent::PlayerDisconnected {
+                player: disconnect.player.to_owned(),
+                grace_until: disconnect.grace_until,
+            });
+        }
+        for disconnect in mark
+            .disconnected
+            .iter()
+            .filter(|known| !self.disconnected.contains(known))
+        {
+            events.push(GameEvent::PlayerReconnected {
+                player: disconnect.player.to_owned(),
+            });
+        }
+        let offers = self.offers();
+        if offers != mark.offers {
+            events.push(GameEvent::OffersChanged(offers));
+        }
+        if self.turn != mark.turn && !self.is_finished() {
+            events.push(GameEvent::TurnChanged {
+                player: self.current.to_owned(),
+                turn: self.turn,
+            });
+        }
+        if let Some(result) = self.result.clone().filter(|_| !mark.finished) {
+            events.push(GameEvent::GameOver { result });
+        }
+        events
+    }
+
+    pub fn had_takeback(&self) -> bool {
+        self.history
+            .iter()
+            .any(|entry| matches!(entry, HistoryEntry::Takeback { .. }))
+    }
+
+    pub fn turns(&self) -> usize {
+        self.turn
+    }
+
+    pub fn current_player(&self) -> &str {
+        &self.current
+    }
+
+    /// Time by which the current player has to move before the game is conceded for them.
+    pub fn deadline(&self) -> i64 {
+        self.get_timestamp() + self.settings.move_time_limit()
+    }
+
+    pub fn tracking(&self) -> MatchTracking {
+        MatchTracking {
+            positions: self.positions.clone(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    pub fn set_tracking(&mut self, tracking: MatchTracking) {
+        self.positions = tracking.positions;
+        self.snapshots = tracking.snapshots;
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
         self.timestamp
     }
-    fn set_timestamp(&mut self, timestamp: i64) {
+    pub fn set_timestamp(&mut self, timestamp: i64) {
         self.timestamp = timestamp
     }
 }
 
 impl QuoridorMatch {
     fn move_player(&mut self, player: &str, new_position: (usize, usize)) -> PlayerMoveResult {
-        if self.current != player {
-            return PlayerMoveResult::Disallowed;
+        if let Err(error) = self.check_turn(player) {
+            return PlayerMoveResult::Disallowed(error);
         }
-        if player == self.up_player {
-            if self.game.try_moving_up_player(new_position) {
-                self.check_and_set_winner(&new_position, 8);
-                return PlayerMoveResult::Ok;
-            }
-        } else if self.game.try_moving_down_player(new_position) {
-            self.check_and_set_winner(&new_position, 0);
-            return PlayerMoveResult::Ok;
+        let (moved, goal) = if player == self.up_player {
+            (self.game.try_moving_up_player(new_position), 8)
+        } else {
+            (self.game.try_moving_down_player(new_position), 0)
         };
-        PlayerMoveResult::Disallowed
+        match moved {
+            Ok(()) => {
+                self.check_and_set_winner(&new_position, goal);
+                PlayerMoveResult::Ok
+            }
+            Err(error) => PlayerMoveResult::Disallowed(error),
+        }
     }
 
     fn concede(&mut self, player: &str) -> PlayerMoveResult {
-        if self.winner.is_none() {
-            if player == self.up_player {
-                self.winner = Some(self.down_player.to_owned())
-            } else {
-                self.winner = Some(self.up_player.to_owned())
-            }
+        if !self.contains_player(player) {
+            return PlayerMoveResult::Disallowed(MoveError::NotAPlayer);
+        }
+        if !self.is_finished() {
+            self.set_winner(self.opponent_of(player).to_owned(), WinReason::Concede);
         }
         PlayerMoveResult::GameFinished
     }
 
     fn check_and_set_winner(&mut self, new_position: &(usize, usize), expected: usize) {
         if new_position.0 == expected {
-            self.winner = Some(self.current.to_owned())
+            self.set_winner(self.current.to_owned(), WinReason::ReachedGoal)
+        }
+    }
+
+    fn snapshot(&self, player: &str) -> TurnSnapshot {
+        TurnSnapshot {
+            player: player.to_owned(),
+            game: self.game.clone(),
+            turn: self.turn,
+            current: self.current.to_owned(),
+            only_player_moves_allowed: self.only_player_moves_allowed,
+            positions: self.positions.len(),
+        }
+    }
+
+    /// Number of plies to undo so the player's last move is taken back, replies included.
+    fn takeback_plies(&self, player: &str) -> Option<usize> {
+        self.snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.player == player)
+            .map(|idx| self.snapshots.len() - idx)
+    }
+
+    fn apply_takeback(&mut self, player: &str) -> PlayerMoveResult {
+        let plies = match self.takeback_plies(player) {
+            Some(plies) => plies,
+            None => return PlayerMoveResult::Disallowed(MoveError::NotAvailable),
+        };
+        let snapshot = match self.snapshots.drain(self.snapshots.len() - plies..).next() {
+            Some(snapshot) => snapshot,
+            None => return PlayerMoveResult::Disallowed(MoveError::NotAvailable),
+        };
+        self.game = snapshot.game;
+        self.turn = snapshot.turn;
+        self.current = snapshot.current;
+        self.only_player_moves_allowed = snapshot.only_player_moves_allowed;
+        self.positions.truncate(snapshot.positions);
+        self.draw_offer = None;
+        self.takeback_offer = None;
+        self.history.push(HistoryEntry::Takeback {
+            player: player.to_owned(),
+            plies,
+        });
+        self.refresh_timestamp();
+        PlayerMoveResult::Ok
+    }
+
+    fn set_winner(&mut self, winner: String, reason: WinReason) {
+        self.result = Some(MatchResult::Win { winner, reason });
+    }
+
+    /// Threefold repetition is possible only once walls stop being placed, walls never move.
+    fn record_position(&mut self) {
+        let position = format!(
+            "{:?}{:?}{}{}{}",
+            self.game.up_player,
+            self.game.down_player,
+            self.game.horizontal_walls.len(),
+            self.game.vertical_walls.len(),
+            self.current
+        );
+        let repeated = self.positions.iter().filter(|known| **known == position).count() + 1;
+        self.positions.push(position);
+        if repeated >= REPETITION_LIMIT {
+            self.result = Some(MatchResult::Draw {
+                reason: DrawReason::Repetition,
+            });
         }
     }
 
     fn new_h_wall(&mut self, player: &str, position: (usize, usize)) -> PlayerMoveResult {
-        let player_status = self.player_is_valid(player);
-        if !matches!(player_status, PlayerMoveResult::Ok) {
-            return player_status;
+        if let Err(error) = self.check_wall(player) {
+            return PlayerMoveResult::Disallowed(error);
         }
-        if self.only_player_moves_allowed || !self.game.new_h_wall(position) {
-            return PlayerMoveResult::Disallowed;
+        if let Err(error) = self.game.new_h_wall(position) {
+            return PlayerMoveResult::Disallowed(error);
         };
         self.remove_border_from_player(player);
-        player_status
+        PlayerMoveResult::Ok
     }
 
     fn new_v_wall(&mut self, player: &str, position: (usize, usize)) -> PlayerMoveResult {
-        let player_status = self.player_is_valid(player);
-        if !matches!(player_status, PlayerMoveResult::Ok) {
-            return player_status;
+        if let Err(error) = self.check_wall(player) {
+            return PlayerMoveResult::Disallowed(error);
         }
-        if self.only_player_moves_allowed || !self.game.new_v_wall(position) {
-            return PlayerMoveResult::Disallowed;
+        if let Err(error) = self.game.new_v_wall(position) {
+            return PlayerMoveResult::Disallowed(error);
         }
         self.remove_border_from_player(player);
-        player_status
+        PlayerMoveResult::Ok
     }
 
-    fn player_is_valid(&self, player: &str) -> PlayerMoveResult {
+    fn check_turn(&self, player: &str) -> Result<(), MoveError> {
+        if !self.contains_player(player) {
+            return Err(MoveError::NotAPlayer);
+        }
         if player != self.current {
-            return PlayerMoveResult::Disallowed;
+            return Err(MoveError::NotYourTurn);
         }
+        Ok(())
+    }
+
+    fn check_wall(&self, player: &str) -> Result<(), MoveError> {
+        self.check_turn(player)?;
         if player == self.up_player && 1 > self.game.up_player_free_walls
             || player == self.down_player && 1 > self.game.down_player_free_walls
         {
-            return PlayerMoveResult::Disallowed;
+            return Err(MoveError::NoWallsLeft);
         }
-        PlayerMoveResult::Ok
+        if self.only_player_moves_allowed {
+            return Err(MoveError::WallsDisallowedWhilePawnsOverlap);
+        }
+        Ok(())
     }
 
     fn remove_border_from_player(&mut self, player: &str) {
@@ -157,13 +787,24 @@ impl QuoridorMatch {
 
     fn end_turn(&mut self) {
         self.turn += 1;
+        self.draw_offer = None;
+        self.takeback_offer = None;
         if self.game.up_player == self.game.down_player {
             self.only_player_moves_allowed = true;
         } else {
             self.only_player_moves_allowed = false;
             self.switch_player()
         }
-        if self.current == cpu::CPU {
+        if self.is_finished() {
+            return;
+        }
+        self.record_position();
+        if !self.is_finished() && self.settings.move_limit.is_some_and(|limit| self.turn >= limit) {
+            self.result = Some(MatchResult::Draw {
+                reason: DrawReason::MoveLimit,
+            });
+        }
+        if !self.is_finished() && self.current == cpu::CPU {
             self.cpu_player_move();
         }
     }
@@ -176,8 +817,20 @@ impl QuoridorMatch {
         }
     }
 
+    /// The CPU plays from the bottom, when it is the up player it looks at the board upside down.
     fn cpu_player_move(&mut self) {
-        let cpu_move = cpu::CpuPlayer::get_cpu_move(&self.game, self.only_player_moves_allowed);
+        let cpu_is_up = self.up_player == cpu::CPU;
+        let game = if cpu_is_up {
+            self.game.mirrored()
+        } else {
+            self.game.clone()
+        };
+        let cpu_move = cpu::CpuPlayer::get_cpu_move(&game, self.only_player_moves_allowed, self.settings.difficulty);
+        let cpu_move = if cpu_is_up {
+            cpu::mirror_move(cpu_move)
+        } else {
+            cpu_move
+        };
         self.make_move(cpu_move, cpu::CPU);
     }
 }
@@ -220,12 +873,12 @@ mod test {
     #[test]
     fn create_wall() {
         let mut new_game = Quoridor::new();
-        assert!(new_game.new_h_wall((1, 0)));
-        assert!(new_game.new_h_wall((1, 2)));
-        assert!(new_game.new_h_wall((1, 4)));
-        assert!(new_game.new_h_wall((1, 6)));
-        assert!(new_game.new_v_wall((2, 6)));
-        assert!(!new_game.new_h_wall((3, 7)));
+        assert!(new_game.new_h_wall((1, 0)).is_ok());
+        assert!(new_game.new_h_wall((1, 2)).is_ok());
+        assert!(new_game.new_h_wall((1, 4)).is_ok());
+        assert!(new_game.new_h_wall((1, 6)).is_ok());
+        assert!(new_game.new_v_wall((2, 6)).is_ok());
+        assert_eq!(new_game.new_h_wall((3, 7)), Err(MoveError::WallBlocksPath));
     }
 
     #[test]
@@ -246,17 +899,81 @@ mod test {
             (0, 4),
         ]);
         let mut new_game = Quoridor::new();
-        new_game.new_h_wall((1, 0));
-        new_game.new_h_wall((1, 2));
-        new_game.new_h_wall((1, 4));
-        new_game.new_h_wall((1, 6));
-        new_game.new_v_wall((2, 6));
+        new_game.new_h_wall((1, 0)).unwrap();
+        new_game.new_h_wall((1, 2)).unwrap();
+        new_game.new_h_wall((1, 4)).unwrap();
+        new_game.new_h_wall((1, 6)).unwrap();
+        new_game.new_v_wall((2, 6)).unwrap();
         assert_eq!(new_game.get_shortest_path((0, 4), 8), expected_path);
     }
 
+    #[test]
+    fn legal_moves_match_the_engine() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        let legal = new_game.legal_moves();
+        assert_eq!(legal.player, "pl1");
+        assert_eq!(legal.pawn, vec![(0, 5), (1, 4), (0, 3)]);
+        assert_eq!(legal.horizontal_walls.len(), 64);
+        assert_eq!(legal.vertical_walls.len(), 64);
+
+        for wall in [(1, 0), (1, 2), (1, 4), (1, 6)] {
+            assert!(new_game.game.new_h_wall(wall).is_ok());
+        }
+        assert!(new_game.game.new_v_wall((2, 6)).is_ok());
+        let legal = new_game.legal_moves();
+        assert!(!legal.horizontal_walls.contains(&(3, 7)));
+        for wall in legal.horizontal_walls {
+            assert!(new_game.game.clone().new_h_wall(wall).is_ok());
+        }
+        for wall in legal.vertical_walls {
+            assert!(new_game.game.clone().new_v_wall(wall).is_ok());
+        }
+        new_game.make_move(PlayerMove::Concede, "pl1");
+        assert!(new_game.legal_moves().pawn.is_empty());
+    }
+
+    #[test]
+    fn rejected_moves_explain_why() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        let mut rejection = |player_move, player| match new_game.make_move(player_move, player) {
+            PlayerMoveResult::Disallowed(reason) => Some(reason),
+            _ => None,
+        };
+        assert_eq!(
+            rejection(PlayerMove::QuoridorMove { row: 7, col: 4 }, "pl2"),
+            Some(MoveError::NotYourTurn)
+        );
+        assert_eq!(
+            rejection(PlayerMove::QuoridorMove { row: 2, col: 4 }, "pl1"),
+            Some(MoveError::PawnNotAdjacent)
+        );
+        assert_eq!(
+            rejection(PlayerMove::QuoridorWallH { row: 8, col: 0 }, "pl1"),
+            Some(MoveError::WallOutOfBounds)
+        );
+        assert_eq!(rejection(PlayerMove::QuoridorWallH { row: 0, col: 3 }, "pl1"), None);
+        assert_eq!(
+            rejection(PlayerMove::QuoridorWallH { row: 0, col: 4 }, "pl2"),
+            Some(MoveError::WallOverlaps)
+        );
+        assert_eq!(
+            rejection(PlayerMove::QuoridorWallV { row: 0, col: 3 }, "pl2"),
+            Some(MoveError::WallCrosses)
+        );
+        assert_eq!(
+            rejection(PlayerMove::QuoridorMove { row: 7, col: 4 }, "spectator"),
+            Some(MoveError::NotAPlayer)
+        );
+        assert_eq!(rejection(PlayerMove::QuoridorMove { row: 7, col: 4 }, "pl2"), None);
+        assert_eq!(
+            rejection(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1"),
+            Some(MoveError::PawnBlockedByWall)
+        );
+    }
+
     #[test]
     fn new_match_player_moves() {
-        let mut new_game = QuoridorMatch::new(&vec!["pl1".to_owned(), "pl2".to_owned()]);
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
         let result = matches!(
             new_game.make_move(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1"),
             PlayerMoveResult::Ok
@@ -273,7 +990,7 @@ mod test {
 
     #[test]
     fn new_match_make_borders() {
-        let mut new_game = QuoridorMatch::new(&vec!["pl1".to_owned(), "pl2".to_owned()]);
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
         let result = matches!(
             new_game.make_move(PlayerMove::QuoridorWallH { row: 1, col: 0 }, "pl1"),
             PlayerMoveResult::Ok
@@ -314,12 +1031,170 @@ mod test {
 
     #[test]
     fn test_cpu() {
-        let mut new_game = QuoridorMatch::new(&vec!["pl1".to_owned()]);
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned()], MatchSettings::default());
         new_game.make_move(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1");
-        let cpu_move = cpu::CpuPlayer::get_cpu_move(&new_game.game, false);
+        let cpu_move = cpu::CpuPlayer::get_cpu_move(&new_game.game, false, cpu::CpuDifficulty::Hard);
         if let PlayerMove::QuoridorWallH { row, col } = cpu_move {
             assert_eq!((row, col), (2, 3))
         }
+        let easy_move = cpu::CpuPlayer::get_cpu_move(&new_game.game, false, cpu::CpuDifficulty::Easy);
+        assert!(matches!(easy_move, PlayerMove::QuoridorMove { .. }));
         assert_eq!(new_game.current, "pl1".to_owned());
     }
+
+    #[test]
+    fn cpu_takes_turns_opening() {
+        let mut first = QuoridorMatch::new(&["pl1".to_owned()], MatchSettings::default());
+        first.make_move(PlayerMove::Concede, "pl1");
+        let lobby = first.rematch_lobby("pl1").unwrap();
+        assert_eq!(lobby, vec![cpu::CPU.to_owned(), "pl1".to_owned()]);
+        let second = QuoridorMatch::new(&lobby, MatchSettings::default());
+        assert_eq!(second.up_player, cpu::CPU);
+        assert_eq!(second.game.up_player, (1, 4));
+        assert_eq!(second.current, "pl1");
+        assert_eq!(second.rematch_lobby("pl1").unwrap(), vec!["pl1".to_owned()]);
+    }
+
+    #[test]
+    fn mirrored_board_keeps_the_walls_in_place() {
+        let mut game = Quoridor::new();
+        game.up_player = (2, 3);
+        game.up_player_free_walls = 4;
+        game.horizontal_walls.push((0, 1));
+        game.vertical_walls.push((6, 5));
+        let mirrored = game.mirrored();
+        assert_eq!((mirrored.up_player, mirrored.down_player), ((0, 4), (6, 3)));
+        assert_eq!(
+            (mirrored.up_player_free_walls, mirrored.down_player_free_walls),
+            (WALLS_PER_PLAYER, 4)
+        );
+        assert_eq!(
+            (mirrored.horizontal_walls[0], mirrored.vertical_walls[0]),
+            ((7, 1), (1, 5))
+        );
+        assert!(matches!(
+            cpu::mirror_move(PlayerMove::QuoridorWallH { row: 7, col: 1 }),
+            PlayerMove::QuoridorWallH { row: 0, col: 1 }
+        ));
+    }
+
+    #[test]
+    fn events_describe_the_change() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        let mark = new_game.mark();
+        new_game.make_move(PlayerMove::QuoridorWallV { row: 2, col: 6 }, "pl1");
+        let events = new_game.events_since(&mark);
+        assert!(matches!(
+            events.as_slice(),
+            [
+                GameEvent::WallPlaced {
+                    orientation: WallOrientation::Vertical,
+                    row: 2,
+                    col: 6,
+                    ..
+                },
+                GameEvent::TurnChanged { turn: 1, .. }
+            ]
+        ));
+        let mark = new_game.mark();
+        new_game.make_move(PlayerMove::QuoridorMove { row: 4, col: 4 }, "pl2");
+        assert!(new_game.events_since(&mark).is_empty());
+        new_game.make_move(PlayerMove::Concede, "pl2");
+        assert!(matches!(
+            new_game.events_since(&mark).as_slice(),
+            [GameEvent::GameOver { .. }]
+        ));
+    }
+
+    #[test]
+    fn disconnect_pauses_the_clock() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        new_game.set_timestamp(1000);
+        new_game.player_disconnected("pl1", 1010);
+        assert!(new_game.is_disconnected("pl1"));
+        new_game.player_reconnected("pl1", 1030);
+        assert!(!new_game.is_disconnected("pl1"));
+        assert_eq!(new_game.get_timestamp(), 1020);
+        new_game.player_disconnected("pl1", 1100);
+        new_game.player_reconnected("pl1", 1100 + DISCONNECT_GRACE * 3);
+        assert_eq!(new_game.get_timestamp(), 1020 + DISCONNECT_GRACE);
+    }
+
+    #[test]
+    fn draw_by_agreement() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        assert!(matches!(new_game.offer_draw("pl1"), PlayerMoveResult::Ok));
+        assert!(matches!(new_game.accept_draw("pl1"), PlayerMoveResult::Disallowed(_)));
+        assert!(matches!(new_game.accept_draw("pl2"), PlayerMoveResult::GameFinished));
+        assert_eq!(new_game.outcome_for("pl1"), Some(Outcome::Draw));
+        assert_eq!(
+            new_game.result,
+            Some(MatchResult::Draw {
+                reason: DrawReason::Agreement
+            })
+        );
+    }
+
+    #[test]
+    fn draw_by_repetition() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        for _ in 0..2 {
+            new_game.make_move(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1");
+            new_game.make_move(PlayerMove::QuoridorMove { row: 7, col: 4 }, "pl2");
+            new_game.make_move(PlayerMove::QuoridorMove { row: 0, col: 4 }, "pl1");
+            new_game.make_move(PlayerMove::QuoridorMove { row: 8, col: 4 }, "pl2");
+        }
+        assert_eq!(
+            new_game.result,
+            Some(MatchResult::Draw {
+                reason: DrawReason::Repetition
+            })
+        );
+    }
+
+    #[test]
+    fn draw_by_move_limit() {
+        let settings = MatchSettings {
+            move_limit: Some(2),
+            ..MatchSettings::default()
+        };
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], settings);
+        new_game.make_move(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1");
+        assert!(!new_game.is_finished());
+        new_game.make_move(PlayerMove::QuoridorMove { row: 7, col: 4 }, "pl2");
+        assert_eq!(new_game.outcome_for("pl2"), Some(Outcome::Draw));
+    }
+
+    #[test]
+    fn takeback_restores_state() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        new_game.make_move(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1");
+        new_game.make_move(PlayerMove::QuoridorWallH { row: 1, col: 0 }, "pl2");
+        assert!(matches!(new_game.request_takeback("pl2"), PlayerMoveResult::Ok));
+        assert!(matches!(
+            new_game.accept_takeback("pl2"),
+            PlayerMoveResult::Disallowed(_)
+        ));
+        assert!(matches!(new_game.accept_takeback("pl1"), PlayerMoveResult::Ok));
+        assert_eq!(new_game.current, "pl2");
+        assert_eq!(new_game.turn, 1);
+        assert_eq!(new_game.game.down_player_free_walls, WALLS_PER_PLAYER);
+        assert!(new_game.game.horizontal_walls.is_empty());
+        assert!(matches!(
+            new_game.history.last(),
+            Some(HistoryEntry::Takeback { plies: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn takeback_against_cpu_undoes_reply() {
+        let mut new_game = QuoridorMatch::new(&["pl1".to_owned()], MatchSettings::default());
+        new_game.make_move(PlayerMove::QuoridorMove { row: 1, col: 4 }, "pl1");
+        assert_eq!(new_game.turn, 2);
+        assert!(matches!(new_game.request_takeback("pl1"), PlayerMoveResult::Ok));
+        assert_eq!(new_game.turn, 0);
+        assert_eq!(new_game.current, "pl1");
+        assert_eq!(new_game.game.up_player, Quoridor::new().up_player);
+        assert_eq!(new_game.game.down_player_free_walls, WALLS_PER_PLAYER);
+    }
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use tokio::sync::watch;
+
+use crate::errors::StateError;
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SocialRecord {
+    pub friends: Vec<String>,
+    pub incoming: Vec<String>,
+    pub outgoing: Vec<String>,
+    pub following: Vec<String>,
+    pub blocked: Vec<String>,
+    /// Muted users are only hidden in chat, unlike blocked ones.
+    #[serde(default)]
+    pub muted: Vec<String>,
+}
+
+pub struct Social {
+    db: sled::Db,
+    /// Users hidden in chat for readers with an open chat socket, refreshed whenever their record is saved.
+    hidden: HashMap<String, watch::Sender<Vec<String>>>,
+}
+
+impl Default for Social {
+    fn default() -> Self {
+        Self {
+            db: sled::open("social").expect("Unable to start DB!"),
+            hidden: HashMap::new(),
+        }
+    }
+}
+
+impl Social {
+    pub fn get(&self, email: &str) -> SocialRecord {
+        self.db
+            .get(email)
+            .ok()
+            .flatten()
+            .and_then(|record| {
+                std::str::from_utf8(&record)
+                    .ok()
+                    .and_then(|data| from_str::<SocialRecord>(data).ok())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sending a request to someone who already asked us accepts their request instead.
+    pub fn send_request(&self, from: &str, to: &str) -> Result<(), StateError> {
+        if from == to {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        if self.is_blocked_between(from, to) {
+            return Err(StateError::Unauthorized);
+        }
+        let mut sender = self.get(from);
+        let mut receiver = self.get(to);
+        if sender.friends.iter().any(|friend| friend == to) {
+            return Err(StateError::AlreadyTaken);
+        }
+        if sender.incoming.iter().any(|request| request == to) {
+            return self.accept_request(from, to);
+        }
+        add(&mut sender.outgoing, to);
+        add(&mut receiver.incoming, from);
+        self.save(from, &sender)?;
+        self.save(to, &receiver)
+    }
+
+    pub fn accept_request(&self, user: &str, from: &str) -> Result<(), StateError> {
+        let mut receiver = self.get(user);
+        let mut sender = self.get(from);
+        if !receiver.incoming.iter().any(|request| request == from) {
+            return Err(StateError::NotFound);
+        }
+        remove(&mut receiver.incoming, from);
+        remove(&mut sender.outgoing, user);
+        add(&mut receiver.friends, from);
+        add(&mut sender.friends, user);
+        self.save(user, &receiver)?;
+        self.save(from, &sender)
+    }
+
+    pub fn decline_request(&self, user: &str, from: &str) -> Result<(), StateError> {
+        let mut receiver = self.get(user);
+        let mut sender = self.get(from);
+        if !receiver.incoming.iter().any(|request| request == from) {
+            return Err(StateError::NotFound);
+        }
+        remove(&mut receiver.incoming, from);
+        remove(&mut sender.outgoing, user);
+        self.save(user, &receiver)?;
+        self.save(from, &sender)
+    }
+
+    /// Removes a friend, or cancels/declines a pending request between the two users.
+    pub fn remove_friend(&self, user: &str, other: &str) -> Result<(), StateError> {
+        let mut record = self.get(user);
+        let mut other_record = self.get(other);
+        unlink(&mut record, &mut other_record, user, other);
+        self.save(user, &record)?;
+        self.save(other, &other_record)
+    }
+
+    pub fn follow(&self, user: &str, other: &str) -> Result<(), StateError> {
+        if user == other {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        if self.is_blocked_between(user, other) {
+            return Err(StateError::Unauthorized);
+        }
+        let mut record = self.get(user);
+        add(&mut record.following, other);
+        self.save(user, &record)
+    }
+
+    pub fn unfollow(&self, user: &str, other: &str) -> Result<(), StateError> {
+        let mut record = self.get(user);
+        remove(&mut record.following, other);
+        self.save(user, &record)
+    }
+
+    /// Blocking also drops the friendship, pending requests and follows in both directions.
+    pub fn block(&self, user: &str, other: &str) -> Result<(), StateError> {
+        if user == other {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        let mut record = self.get(user);
+        let mut other_record = self.get(other);
+        unlink(&mut record, &mut other_record, user, other);
+        remove(&mut record.following, other);
+        remove(&mut other_record.following, user);
+        add(&mut record.blocked, other);
+        self.save(user, &record)?;
+        self.save(other, &other_record)
+    }
+
+    pub fn unblock(&self, user: &str, other: &str) -> Result<(), StateError> {
+        let mut record = self.get(user);
+        remove(&mut record.blocked, other);
+        self.save(user, &record)
+    }
+
+    pub fn mute(&self, user: &str, other: &str) -> Result<(), StateError> {
+        if user == other {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        let mut record = self.get(user);
+        add(&mut record.muted, other);
+        self.save(user, &record)
+    }
+
+    pub fn unmute(&self, user: &str, other: &str) -> Result<(), StateError> {
+        let mut record = self.get(user);
+        remove(&mut record.muted, other);
+        self.save(user, &record)
+    }
+
+    /// Users whose chat messages are hidden from `user`.
+    /// Blocked and muted users of the reader, kept up to date while the receiver is alive.
+    pub fn watch_hidden(&mut self, user: &str) -> watch::Receiver<Vec<String>> {
+        if let Some(sender) = self.hidden.get(user) {
+            return sender.subscribe();
+        }
+        let (sender, receiver) = watch::channel(hidden_in_chat(&self.get(user)));
+        self.hidden.insert(user.to_owned(), sender);
+        receiver
+    }
+
+    /// Drops watches without any chat socket left.
+    pub fn clean_up(&mut self) {
+        self.hidden.retain(|_, sender| sender.receiver_count() > 0);
+    }
+
+    pub fn has_blocked(&self, user: &str, other: &str) -> bool {
+        self.get(user).blocked.iter().any(|blocked| blocked == other)
+    }
+
+    pub fn is_blocked_between(&self, first: &str, second: &str) -> bool {
+        self.has_blocked(first, second) || self.has_blocked(second, first)
+    }
+
+    fn save(&self, email: &str, record: &SocialRecord) -> Result<(), StateError> {
+        let value = to_string(record).map_err(|_| StateError::ServerError)?;
+        self.db
+            .insert(email, value.as_bytes())
+            .map_err(|_| StateError::ServerError)?;
+        if let Some(sender) = self.hidden.get(email) {
+            sender.send_replace(hidden_in_chat(record));
+        }
+        Ok(())
+    }
+}
+
+fn hidden_in_chat(record: &SocialRecord) -> Vec<String> {
+    record.blocked.iter().chain(&record.muted).cloned().collect()
+}
+
+fn unlink(record: &mut SocialRecord, other_record: &mut SocialRecord, user: &str, other: &str) {
+    for list in [&mut record.friends, &mut record.incoming, &mut record.outgoing] {
+        remove(list, other);
+    }
+    for list in [
+        &mut other_record.friends,
+        &mut other_record.incoming,
+        &mut other_record.outgoing,
+    ] {
+        remove(list, user);
+    }
+}
+
+fn add(list: &mut Vec<String>, email: &str) {
+    if !list.iter().any(|known| known == email) {
+        list.push(email.to_owned());
+    }
+}
+
+fn remove(list: &mut Vec<String>, email: &str) {
+    list.retain(|known| known != email);
+}
@@ -1,37 +1,193 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex, RwLock};
 extern crate rand;
+use crate::achievements::Achievements;
+use crate::archive::MatchArchive;
 use crate::auth::Users;
+use crate::challenges::{Challenge, ChallengeCreate, Challenges};
+use crate::chat::{spectator_channel, ChatAccess, ChatChannel, ChatHistory, LOBBY_CHANNEL, REPLAY_LEN};
+use crate::correspondence::CorrespondenceGames;
+use crate::direct::{Conversation, DirectMessage, DirectMessageView, DirectMessages, DirectRelay};
 use crate::errors::StateError;
-use crate::messages::{ChatMessage, PlayerMoveResult, UserContext};
-use crate::quoridor::QuoridorMatch;
-use crate::leaderboard::LeaderBoard;
+use crate::leaderboard::{LeaderBoard, MatchSummary};
+use crate::matchmaking::{MatchmakingQueue, QueueEntry};
+use crate::messages::{
+    ChatFrame, ChatMessage, Contact, GameEvent, LobbyEvent, MatchReplay, MatchRequest, PlayerMove, PlayerMoveResult,
+    PublicProfile, QueueHost, QuoridorMatchMeta, SequencedEvent, SocialOverview, SystemEvent, UserContext, UserMatch,
+};
+use crate::moderation::{ChatRejection, ChatReport, Moderation, ReportCreate, MAX_REACTION_LEN};
+use crate::presence::{Activity, Presence};
+use crate::quoridor::{cpu::CPU, HistoryEntry, MatchResult, MatchSettings, MoveError, QuoridorMatch};
+use crate::social::Social;
+use crate::tournament::{Tournament, TournamentCreate, TournamentView};
 use rand::{distributions::Alphanumeric, Rng};
 use regex::Regex;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tower_cookies::Cookie;
 
 const ID_LEN: usize = 8;
 const TOKEN_LEN: usize = 16;
 const SECONDS_IN_DAY: i64 = 24 * 60 * 60;
+const REPORT_CONTEXT_LEN: usize = 10;
+const GAME_CAPACITY_VAR: &str = "GAME_CHANNEL_CAPACITY";
+const DEFAULT_GAME_CAPACITY: usize = 16;
+const LOBBY_CAPACITY_VAR: &str = "LOBBY_CHANNEL_CAPACITY";
+const DEFAULT_LOBBY_CAPACITY: usize = 64;
+const GAME_REPLAY_LEN: usize = 64;
 
 type TimeStamp = i64;
-type QuoridorPackage = (Arc<RwLock<QuoridorMatch>>, broadcast::Sender<PlayerMoveResult>);
-type QuoridorQue = Arc<Mutex<HashMap<String, tokio::sync::oneshot::Sender<String>>>>;
+type QuoridorPackage = (Arc<RwLock<QuoridorMatch>>, GameChannel);
+type QuoridorQue = Arc<Mutex<HashMap<String, (QueueHost, tokio::sync::oneshot::Sender<String>)>>>;
+type TournamentPackage = (Tournament, broadcast::Sender<TournamentView>);
+
+/// Broadcast capacity from an env variable, the default is used when it is unset or invalid.
+pub fn channel_capacity(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .filter(|capacity| *capacity > 0)
+        .unwrap_or(default)
+}
+
+pub enum GameUpdate {
+    Events(Vec<SequencedEvent>),
+    /// The subscriber fell behind, the skipped events are dropped and a full snapshot should be sent instead.
+    Lagged,
+}
+
+/// Waits for a game update, `None` once the game is gone.
+pub async fn game_update(receiver: &mut broadcast::Receiver<Vec<SequencedEvent>>) -> Option<GameUpdate> {
+    match receiver.recv().await {
+        Ok(events) => Some(GameUpdate::Events(events)),
+        Err(RecvError::Lagged(_)) => {
+            *receiver = receiver.resubscribe();
+            Some(GameUpdate::Lagged)
+        }
+        Err(RecvError::Closed) => None,
+    }
+}
+
+/// Match broadcast plus the latest events, kept so reconnecting clients can catch up without a snapshot.
+#[derive(Clone)]
+pub struct GameChannel {
+    sender: broadcast::Sender<Vec<SequencedEvent>>,
+    replay: Arc<Mutex<VecDeque<SequencedEvent>>>,
+}
+
+impl Default for GameChannel {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel::<Vec<SequencedEvent>>(channel_capacity(
+                GAME_CAPACITY_VAR,
+                DEFAULT_GAME_CAPACITY,
+            ))
+            .0,
+            replay: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+}
+
+impl GameChannel {
+    pub fn subscribe(&self) -> broadcast::Receiver<Vec<SequencedEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Numbers the events with the match sequence and broadcasts them.
+    /// Empty batches are still sent, legacy subscribers refresh their snapshot on every update.
+    pub fn publish(&self, game: &mut QuoridorMatch, events: Vec<GameEvent>) {
+        let mut replay = self.replay.lock().unwrap();
+        let batch: Vec<SequencedEvent> = events
+            .into_iter()
+            .map(|event| {
+                game.seq += 1;
+                SequencedEvent { seq: game.seq, event }
+            })
+            .collect();
+        replay.extend(batch.iter().cloned());
+        while replay.len() > GAME_REPLAY_LEN {
+            replay.pop_front();
+        }
+        let _ = self.sender.send(batch);
+    }
+
+    /// Events after `last_seq` up to `current`, `None` when some of them are no longer kept.
+    pub fn missed(&self, last_seq: u64, current: u64) -> Option<Vec<SequencedEvent>> {
+        if last_seq > current {
+            return None;
+        }
+        let replay = self.replay.lock().unwrap();
+        let oldest = replay.front().map_or(current + 1, |event| event.seq);
+        if last_seq + 1 < oldest && last_seq < current {
+            return None;
+        }
+        Some(replay.iter().filter(|event| event.seq > last_seq).cloned().collect())
+    }
+}
+
+pub struct LobbyEvents(broadcast::Sender<LobbyEvent>);
+
+impl Default for LobbyEvents {
+    fn default() -> Self {
+        Self(broadcast::channel::<LobbyEvent>(channel_capacity(LOBBY_CAPACITY_VAR, DEFAULT_LOBBY_CAPACITY)).0)
+    }
+}
+
+impl LobbyEvents {
+    pub fn send(&self, event: LobbyEvent) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LobbyEvent> {
+        self.0.subscribe()
+    }
+}
 
 #[derive(Default)]
 pub struct AppState {
     pub quoridor_games: Arc<Mutex<HashMap<String, QuoridorPackage>>>,
     pub quoridor_que: QuoridorQue,
-    pub chat_channel: Arc<RwLock<HashMap<String, broadcast::Sender<ChatMessage>>>>,
+    pub matchmaking: Arc<Mutex<MatchmakingQueue>>,
+    pub challenges: Arc<Mutex<Challenges>>,
+    pub tournaments: Arc<Mutex<HashMap<String, TournamentPackage>>>,
+    pub lobby_events: LobbyEvents,
+    pub correspondence: Arc<Mutex<CorrespondenceGames>>,
+    pub chat_channel: Arc<RwLock<HashMap<String, ChatChannel>>>,
+    pub chat_history: Arc<Mutex<ChatHistory>>,
+    pub archive: Arc<Mutex<MatchArchive>>,
+    pub moderation: Arc<Mutex<Moderation>>,
+    pub direct_messages: Arc<Mutex<DirectMessages>>,
+    pub direct_relay: Arc<Mutex<DirectRelay>>,
     pub users: Arc<Mutex<Users>>,
     pub leaderboard: Arc<Mutex<LeaderBoard>>,
+    pub achievements: Arc<Mutex<Achievements>>,
+    pub social: Arc<Mutex<Social>>,
+    pub presence: Arc<Mutex<Presence>>,
     sessions: Arc<Mutex<HashMap<String, (UserContext, TimeStamp)>>>,
 }
 
 impl AppState {
     pub fn new_as_arc() -> Arc<Self> {
-        Arc::new(Self::default())
+        let state = Self::default();
+        state.create_chat_from_id(LOBBY_CHANNEL, ChatAccess::Public);
+        state.quoridor_restore_correspondence();
+        Arc::new(state)
+    }
+
+    fn quoridor_restore_correspondence(&self) {
+        let stored = self.correspondence.lock().unwrap().load_all();
+        for (id, game) in stored {
+            let channel = GameChannel::default();
+            let access = self.chat_history.lock().unwrap().get_access(&id);
+            self.create_chat_from_id(&id, access);
+            if !game.settings.private {
+                self.create_chat_from_id(&spectator_channel(&id), ChatAccess::Public);
+            }
+            self.quoridor_games
+                .lock()
+                .unwrap()
+                .insert(id.to_owned(), (Arc::new(RwLock::new(game)), channel));
+        }
     }
 
     pub fn user_create_with_session(
@@ -91,7 +247,9 @@ impl AppState {
             email: username,
             username: "GUEST".to_owned(),
             auth_token: token.to_owned(),
+            user_id: None,
             active_match: None,
+            active_matches: Vec::new(),
         };
         sessions.insert(
             token,
@@ -112,62 +270,877 @@ impl AppState {
             })
     }
 
-    fn create_chat_from_id(&self, chat_id: &str) {
+    pub fn user_is_online(&self, email: &str) -> bool {
+        self.presence.lock().unwrap().is_online(email)
+    }
+
+    pub fn online_count(&self) -> usize {
+        self.presence.lock().unwrap().online_count()
+    }
+
+    pub fn social_overview(&self, email: &str) -> SocialOverview {
+        let record = self.social.lock().unwrap().get(email);
+        let contacts = |emails: Vec<String>, with_activity: bool| -> Vec<Contact> {
+            emails
+                .into_iter()
+                .filter_map(|email| self.social_contact(email, with_activity))
+                .collect()
+        };
+        SocialOverview {
+            friends: contacts(record.friends, true),
+            incoming: contacts(record.incoming, false),
+            outgoing: contacts(record.outgoing, false),
+            following: contacts(record.following, true),
+            blocked: contacts(record.blocked, false),
+            muted: contacts(record.muted, false),
+        }
+    }
+
+    /// Contacts whose account is gone are left out.
+    fn social_contact(&self, email: String, with_activity: bool) -> Option<Contact> {
+        let users = self.users.lock().unwrap();
+        let id = users.get_public_id(&email)?;
+        let username = users.get_username(&email)?;
+        let hidden = users.get_privacy(&email).hide_online_status;
+        drop(users);
+        let activity = if with_activity && !hidden {
+            self.presence.lock().unwrap().activity(&email)
+        } else {
+            None
+        };
+        // a private game would give its id away, its players show as online
+        let activity = activity.map(|activity| match activity {
+            Activity::Playing { match_id } if self.quoridor_is_private(&match_id) => Activity::Online,
+            activity => activity,
+        });
+        Some(Contact { id, username, activity })
+    }
+
+    pub fn social_is_blocked(&self, first: &str, second: &str) -> bool {
+        self.social.lock().unwrap().is_blocked_between(first, second)
+    }
+
+    pub fn user_public_profile(&self, user_id: &str) -> Result<PublicProfile, StateError> {
+        let user = self.users.lock().unwrap().get_public(user_id)?;
+        let leaderboard = self.leaderboard.lock().unwrap();
+        let (rating, wins, loses, draws) = leaderboard
+            .get_by_email(&user.email)
+            .map(|record| (record.rating, record.wins, record.loses, record.draws))
+            .unwrap_or((leaderboard.get_rating(&user.email), 0, 0, 0));
+        let recent_matches = if user.privacy.hide_match_history {
+            None
+        } else {
+            Some(leaderboard.get_history(&user.email))
+        };
+        drop(leaderboard);
+        let (online, live_match) = if user.privacy.hide_online_status {
+            (None, None)
+        } else {
+            (
+                Some(self.user_is_online(&user.email)),
+                self.quoridor_get_public_id_by_player(&user.email),
+            )
+        };
+        Ok(PublicProfile {
+            id: user_id.to_owned(),
+            username: user.username,
+            joined: user.joined,
+            rating,
+            wins,
+            loses,
+            draws,
+            achievements: self.achievements.lock().unwrap().get_by_email(&user.email),
+            recent_matches,
+            live_match,
+            online,
+        })
+    }
+
+    /// Stores the message before broadcasting it, so late joiners get it replayed.
+    /// Retries carrying an already stored nonce return the original message without broadcasting again.
+    pub fn chat_post(
+        &self,
+        chat_id: &str,
+        user: &str,
+        text: &str,
+        nonce: Option<String>,
+    ) -> Result<ChatMessage, ChatRejection> {
+        if !self.chat_access(chat_id).can_write(user) {
+            return Err(ChatRejection::ReadOnly);
+        }
+        if let Some(known) = nonce
+            .as_deref()
+            .and_then(|nonce| self.chat_history.lock().unwrap().find_nonce(chat_id, user, nonce))
+        {
+            return Ok(known);
+        }
+        let now = chrono::Utc::now().timestamp();
+        let text = self.moderation.lock().unwrap().check_post(user, text, now)?;
+        let message = self.chat_history.lock().unwrap().push(
+            chat_id,
+            ChatMessage {
+                id: 0,
+                user: user.to_owned(),
+                message: text,
+                timestamp: now,
+                nonce,
+            },
+        );
+        self.chat_broadcast(chat_id, ChatFrame::Message(message.clone()));
+        Ok(message)
+    }
+
+    /// Typing indicators are only relayed, they are neither stored nor rate limited.
+    pub fn chat_typing(&self, chat_id: &str, user: &str) -> Result<(), ChatRejection> {
+        self.chat_check_writer(chat_id, user)?;
+        self.chat_broadcast(chat_id, ChatFrame::Typing { user: user.to_owned() });
+        Ok(())
+    }
+
+    pub fn chat_react(&self, chat_id: &str, user: &str, message_id: u64, emoji: String) -> Result<(), ChatRejection> {
+        self.chat_check_writer(chat_id, user)?;
+        if emoji.is_empty() || emoji.chars().count() > MAX_REACTION_LEN {
+            return Err(ChatRejection::TooLong { max: MAX_REACTION_LEN });
+        }
+        if self.chat_history.lock().unwrap().get(chat_id, message_id).is_none() {
+            return Err(ChatRejection::UnknownMessage);
+        }
+        self.chat_broadcast(
+            chat_id,
+            ChatFrame::Reaction {
+                user: user.to_owned(),
+                message_id,
+                emoji,
+            },
+        );
+        Ok(())
+    }
+
+    /// Narrates a match event to the players' channel and the spectators' one.
+    pub fn chat_narrate(&self, match_id: &str, event: SystemEvent) {
+        let frame = ChatFrame::system(event);
+        self.chat_broadcast(match_id, frame.clone());
+        self.chat_broadcast(&spectator_channel(match_id), frame);
+    }
+
+    fn chat_check_writer(&self, chat_id: &str, user: &str) -> Result<(), ChatRejection> {
+        if !self.chat_access(chat_id).can_write(user) {
+            return Err(ChatRejection::ReadOnly);
+        }
+        match self
+            .moderation
+            .lock()
+            .unwrap()
+            .muted_until(user, chrono::Utc::now().timestamp())
+        {
+            Some(until) => Err(ChatRejection::Muted { until }),
+            None => Ok(()),
+        }
+    }
+
+    fn chat_broadcast(&self, chat_id: &str, frame: ChatFrame) {
+        if let Some(channel) = self.chat_channel.read().unwrap().get(chat_id) {
+            let _ = channel.sender.send(frame);
+        }
+    }
+
+    /// Stores the reported message with the conversation leading up to it.
+    pub fn chat_report(&self, chat_id: &str, reporter: &str, payload: ReportCreate) -> Result<ChatReport, StateError> {
+        if !self.chat_access(chat_id).can_read(reporter) {
+            return Err(StateError::Unauthorized);
+        }
+        let history = self.chat_history.lock().unwrap();
+        let message = history.get(chat_id, payload.message_id).ok_or(StateError::NotFound)?;
+        let mut context = history.before(chat_id, Some(payload.message_id));
+        drop(history);
+        context.drain(..context.len().saturating_sub(REPORT_CONTEXT_LEN));
+        Ok(self.moderation.lock().unwrap().report(ChatReport {
+            id: 0,
+            channel: chat_id.to_owned(),
+            reporter: reporter.to_owned(),
+            reason: payload.reason,
+            message,
+            context,
+            created: chrono::Utc::now().timestamp(),
+        }))
+    }
+
+    /// Direct messages go through the chat moderation rules and never between blocked users.
+    /// The recipient is addressed by public id.
+    pub fn direct_send(&self, from: &str, to_id: &str, text: &str) -> Result<DirectMessageView, StateError> {
+        let to = self.users.lock().unwrap().get_public(to_id)?.email;
+        if from == to {
+            return Err(StateError::UnsupportedDataType("Same user".into()));
+        }
+        if self.social_is_blocked(from, &to) {
+            return Err(StateError::Unauthorized);
+        }
+        let text = self
+            .moderation
+            .lock()
+            .unwrap()
+            .check_post(from, text, chrono::Utc::now().timestamp())
+            .map_err(StateError::Rejected)?;
+        let message = self.direct_messages.lock().unwrap().push(from, &to, text);
+        self.direct_relay.lock().unwrap().deliver(&message);
+        Ok(self.direct_view(message))
+    }
+
+    /// Reading a conversation marks it as read.
+    pub fn direct_conversation(
+        &self,
+        user: &str,
+        other_id: &str,
+        before: Option<u64>,
+    ) -> Result<Vec<DirectMessageView>, StateError> {
+        let other = self.users.lock().unwrap().get_public(other_id)?.email;
+        let direct_messages = self.direct_messages.lock().unwrap();
+        let conversation = direct_messages.conversation(user, &other, before);
+        direct_messages.mark_read(user, &other);
+        drop(direct_messages);
+        Ok(conversation
+            .into_iter()
+            .map(|message| self.direct_view(message))
+            .collect())
+    }
+
+    pub fn direct_view(&self, message: DirectMessage) -> DirectMessageView {
+        let users = self.users.lock().unwrap();
+        DirectMessageView {
+            id: message.id,
+            from: users.get_public_id(&message.from).unwrap_or_default(),
+            to: users.get_public_id(&message.to).unwrap_or_default(),
+            message: message.message,
+            timestamp: message.timestamp,
+        }
+    }
+
+    /// Conversations with the newest message first, partners whose account is gone are left out.
+    pub fn direct_inbox(&self, user: &str) -> Vec<Conversation> {
+        let direct_messages = self.direct_messages.lock().unwrap();
+        let partners: Vec<(String, u64, Option<DirectMessage>)> = direct_messages
+            .partners(user)
+            .into_iter()
+            .map(|(other, unread)| {
+                let last = direct_messages.last(user, &other);
+                (other, unread, last)
+            })
+            .collect();
+        drop(direct_messages);
+        let mut inbox: Vec<Conversation> = partners
+            .into_iter()
+            .filter_map(|(other, unread, last)| {
+                let users = self.users.lock().unwrap();
+                let user_id = users.get_public_id(&other)?;
+                let username = users.get_username(&other)?;
+                drop(users);
+                Some(Conversation {
+                    user_id,
+                    username,
+                    last: last.map(|last| self.direct_view(last)),
+                    unread,
+                })
+            })
+            .collect();
+        inbox.sort_by_key(|conversation| std::cmp::Reverse(conversation.last.as_ref().map(|last| last.id)));
+        inbox
+    }
+
+    /// Access rules of a live channel, or the stored ones once the channel has been dropped.
+    pub fn chat_access(&self, chat_id: &str) -> ChatAccess {
+        match self.chat_channel.read().unwrap().get(chat_id) {
+            Some(channel) => channel.access.clone(),
+            None => self.chat_history.lock().unwrap().get_access(chat_id),
+        }
+    }
+
+    fn create_chat_from_id(&self, chat_id: &str, access: ChatAccess) {
+        self.chat_history.lock().unwrap().set_access(chat_id, &access);
         self.chat_channel
             .write()
             .unwrap()
-            .insert(chat_id.into(), broadcast::channel::<ChatMessage>(50).0);
+            .insert(chat_id.into(), ChatChannel::new(access));
     }
 
-    pub fn quoridor_new_game(&self, lobby: &Vec<String>) -> Option<String> {
+    pub fn quoridor_new_game(&self, lobby: &[String]) -> Option<String> {
+        self.quoridor_new_game_with_settings(lobby, MatchSettings::default())
+    }
+
+    pub fn quoridor_new_game_with_settings(&self, lobby: &[String], settings: MatchSettings) -> Option<String> {
+        let private = settings.private;
+        let (id, meta) = self.quoridor_insert_game(lobby, settings)?;
+        if !private {
+            self.lobby_events.send(LobbyEvent::MatchStarted(meta));
+        }
+        Some(id)
+    }
+
+    /// Creates the match and its chats without announcing it in the lobby.
+    fn quoridor_insert_game(&self, lobby: &[String], settings: MatchSettings) -> Option<(String, QuoridorMatchMeta)> {
         if lobby.is_empty() {
             return None;
         }
-        let channel = broadcast::channel::<PlayerMoveResult>(1).0;
+        let channel = GameChannel::default();
         let mut id = generate_id(ID_LEN);
-        let new_game = Arc::new(RwLock::new(QuoridorMatch::new(lobby)));
+        let new_game = QuoridorMatch::new(lobby, settings);
         let mut games = self.quoridor_games.lock().unwrap();
         while games.contains_key(&id) {
             id = generate_id(ID_LEN)
         }
-        games.insert(id.to_owned(), (new_game, channel));
+        let meta = QuoridorMatchMeta::from((id.to_owned(), new_game.clone()));
+        let players = vec![new_game.up_player.to_owned(), new_game.down_player.to_owned()];
+        let private = new_game.settings.private;
+        if new_game.settings.is_correspondence() {
+            self.correspondence.lock().unwrap().save(&id, &new_game);
+        }
+        games.insert(id.to_owned(), (Arc::new(RwLock::new(new_game)), channel));
         drop(games);
-        self.create_chat_from_id(&id);
-        Some(id)
+        if private {
+            self.create_chat_from_id(&id, ChatAccess::Private { members: players });
+        } else {
+            self.create_chat_from_id(&id, ChatAccess::Match { players });
+            self.create_chat_from_id(&spectator_channel(&id), ChatAccess::Public);
+        }
+        Some((id, meta))
+    }
+
+    pub fn quoridor_make_move(&self, id: &str, player_move: PlayerMove, player: &str) -> PlayerMoveResult {
+        self.quoridor_make_move_with_state(id, player_move, player).0
     }
 
+    /// Makes the move and returns the match as it is right after it, before anyone else can move.
+    pub fn quoridor_make_move_with_state(
+        &self,
+        id: &str,
+        player_move: PlayerMove,
+        player: &str,
+    ) -> (PlayerMoveResult, Option<QuoridorMatch>) {
+        let (game, channel) = match self.quoridor_get_full(id) {
+            Some(package) => package,
+            None => return (PlayerMoveResult::Disallowed(MoveError::UnknownMatch), None),
+        };
+        let mut game = game.write().unwrap();
+        let was_running = !game.is_finished();
+        let mark = game.mark();
+        let seen = game.history.len();
+        let result = game.make_move(player_move, player);
+        if was_running && game.is_finished() {
+            self.quoridor_settle(id, &mut game);
+        }
+        let events = game.events_since(&mark);
+        let match_result = game.result.clone().filter(|_| was_running);
+        // replies of the CPU land in the history as well
+        let narration: Vec<SystemEvent> = game.history[seen..]
+            .iter()
+            .filter_map(|entry| match entry {
+                HistoryEntry::Move {
+                    player,
+                    player_move: PlayerMove::Concede,
+                } => Some(SystemEvent::Conceded {
+                    user: player.to_owned(),
+                }),
+                HistoryEntry::Move { player, player_move } => Some(SystemEvent::Moved {
+                    user: player.to_owned(),
+                    player_move: player_move.clone(),
+                }),
+                HistoryEntry::Takeback { .. } => None,
+            })
+            .collect();
+        if !events.is_empty() {
+            channel.publish(&mut game, events);
+        }
+        self.quoridor_persist(id, &game);
+        let snapshot = game.clone();
+        drop(game);
+        for event in narration {
+            self.chat_narrate(id, event);
+        }
+        if let Some(match_result) = match_result {
+            self.quoridor_finished(id, match_result, snapshot.settings.private);
+        }
+        (result, Some(snapshot))
+    }
+
+    /// A player's socket count for the match changed, the first socket back ends the disconnect grace period.
+    pub fn quoridor_player_connection(&self, id: &str, player: &str, connected: bool) {
+        let (game, channel) = match self.quoridor_get_full(id) {
+            Some(package) => package,
+            None => return,
+        };
+        let mut game = game.write().unwrap();
+        let mark = game.mark();
+        let now = chrono::Utc::now().timestamp();
+        if connected {
+            game.player_reconnected(player, now);
+        } else {
+            game.player_disconnected(player, now);
+        }
+        let events = game.events_since(&mark);
+        if !events.is_empty() {
+            channel.publish(&mut game, events);
+        }
+        self.quoridor_persist(id, &game);
+    }
+
+    fn quoridor_persist(&self, id: &str, game: &QuoridorMatch) {
+        if game.settings.is_correspondence() {
+            self.correspondence.lock().unwrap().save(id, game);
+        }
+    }
+
+    fn quoridor_finished(&self, id: &str, result: MatchResult, private: bool) {
+        self.chat_narrate(id, SystemEvent::GameFinished { result: result.clone() });
+        if !private {
+            self.lobby_events.send(LobbyEvent::MatchFinished {
+                id: id.to_owned(),
+                result: result.clone(),
+            });
+        }
+        self.tournament_record_results(&[(id.to_owned(), result)]);
+    }
+
+    pub fn matchmaking_join(&self, player: &str) -> Result<UnboundedReceiver<String>, StateError> {
+        let (sender, reciever) = unbounded_channel::<String>();
+        let rating = self.leaderboard.lock().unwrap().get_rating(player);
+        self.matchmaking.lock().unwrap().join(QueueEntry {
+            player: player.to_owned(),
+            rating,
+            joined: chrono::Utc::now().timestamp(),
+            sender,
+        })?;
+        self.matchmaking_pair();
+        Ok(reciever)
+    }
+
+    /// The queue stays locked while a pair is notified, so a partly failed pairing is undone before anyone sees it.
+    /// A player whose opponent vanished goes back in the queue with their original join time.
+    pub fn matchmaking_pair(&self) {
+        let mut que = self.matchmaking.lock().unwrap();
+        let pairs = que.take_pairs(chrono::Utc::now().timestamp(), |first, second| {
+            !self.social_is_blocked(first, second)
+        });
+        for (first, second) in pairs {
+            if !first.sender.is_closed() && !second.sender.is_closed() {
+                let players = [first.player.to_owned(), second.player.to_owned()];
+                if let Some((game, meta)) = self.quoridor_insert_game(&players, MatchSettings::default()) {
+                    let first_notified = first.sender.send(game.to_owned()).is_ok();
+                    let second_notified = second.sender.send(game.to_owned()).is_ok();
+                    if first_notified && second_notified {
+                        self.lobby_events.send(LobbyEvent::MatchStarted(meta));
+                        continue;
+                    }
+                    self.quoridor_drop_by_id(&game);
+                }
+            }
+            for entry in [first, second] {
+                if !entry.sender.is_closed() {
+                    let _ = que.join(entry);
+                }
+            }
+        }
+    }
+
+    /// Whether a match id sent by the queue is final, it is not while the pairing is still in progress.
+    pub fn matchmaking_confirm(&self, game: &str) -> bool {
+        let _que = self.matchmaking.lock().unwrap();
+        self.quoridor_get_full(game).is_some()
+    }
+
+    pub fn challenge_create(&self, host: &UserContext, payload: ChallengeCreate) -> Result<Challenge, StateError> {
+        let invitee = match payload.invitee {
+            Some(invitee_id) => {
+                let invitee = self.users.lock().unwrap().get_public(&invitee_id)?.email;
+                if self.social_is_blocked(&host.email, &invitee) {
+                    return Err(StateError::Unauthorized);
+                }
+                Some((invitee, invitee_id))
+            }
+            None => None,
+        };
+        self.challenges
+            .lock()
+            .unwrap()
+            .create((&host.email, host.user_id.clone()), invitee, payload.settings)
+    }
+
+    pub fn challenge_accept(&self, code: &str, player: &str) -> Result<String, StateError> {
+        let mut challenges = self.challenges.lock().unwrap();
+        let challenge = challenges.acceptable(code, player)?;
+        if self.social_is_blocked(&challenge.host, player) {
+            return Err(StateError::Unauthorized);
+        }
+        let settings = MatchSettings {
+            private: true,
+            ..challenge.settings
+        };
+        let game = self
+            .quoridor_new_game_with_settings(&[challenge.host, player.to_owned()], settings)
+            .ok_or(StateError::ServerError)?;
+        challenges.set_match(code, game.to_owned());
+        Ok(game)
+    }
+
+    pub fn quoridor_match_request(&self, id: &str, request: MatchRequest, player: &str) -> PlayerMoveResult {
+        let (game, channel) = match self.quoridor_get_full(id) {
+            Some(package) => package,
+            None => return PlayerMoveResult::Disallowed(MoveError::UnknownMatch),
+        };
+        let mut game = game.write().unwrap();
+        let was_running = !game.is_finished();
+        let mark = game.mark();
+        let result = match request {
+            MatchRequest::OfferDraw => {
+                let result = game.offer_draw(player);
+                if matches!(result, PlayerMoveResult::Ok) {
+                    self.chat_narrate(
+                        id,
+                        SystemEvent::DrawOffered {
+                            user: player.to_owned(),
+                        },
+                    );
+                }
+                result
+            }
+            MatchRequest::AcceptDraw => game.accept_draw(player),
+            MatchRequest::DeclineDraw => game.decline_draw(player),
+            MatchRequest::RequestTakeback => game.request_takeback(player),
+            MatchRequest::AcceptTakeback => game.accept_takeback(player),
+            MatchRequest::DeclineTakeback => game.decline_takeback(player),
+            MatchRequest::OfferRematch => game.offer_rematch(player),
+            MatchRequest::AcceptRematch => match game.rematch_lobby(player) {
+                Some(_) => PlayerMoveResult::Ok,
+                None => PlayerMoveResult::Disallowed(MoveError::NoPendingOffer),
+            },
+            MatchRequest::DeclineRematch => game.decline_rematch(player),
+        };
+        // the CPU takes every rematch right away
+        let rematch_by = match request {
+            MatchRequest::AcceptRematch => Some(player),
+            MatchRequest::OfferRematch if game.contains_player(CPU) => Some(CPU),
+            _ => None,
+        };
+        let match_result = game.result.clone().filter(|_| was_running);
+        if match_result.is_some() {
+            self.quoridor_settle(id, &mut game);
+        }
+        let events = game.events_since(&mark);
+        if !events.is_empty() {
+            channel.publish(&mut game, events);
+        }
+        self.quoridor_persist(id, &game);
+        let private = game.settings.private;
+        drop(game);
+        if let Some(match_result) = match_result {
+            self.quoridor_finished(id, match_result, private);
+        }
+        match rematch_by {
+            Some(rematch_by) if matches!(result, PlayerMoveResult::Ok) => self.quoridor_rematch(id, rematch_by),
+            _ => result,
+        }
+    }
+
+    /// The new game is created with no match locked, creating it locks the game list and heart_beat
+    /// locks the game list before any match. Whoever links a rematch first wins, a late one is dropped again.
+    fn quoridor_rematch(&self, id: &str, player: &str) -> PlayerMoveResult {
+        let (game, channel) = match self.quoridor_get_full(id) {
+            Some(package) => package,
+            None => return PlayerMoveResult::Disallowed(MoveError::UnknownMatch),
+        };
+        let (lobby, settings) = {
+            let game = game.read().unwrap();
+            let lobby = match game.rematch_lobby(player) {
+                Some(lobby) => lobby,
+                None => return PlayerMoveResult::Disallowed(MoveError::NoPendingOffer),
+            };
+            // sides are already swapped in the lobby, the host preference would swap them back
+            let mut settings = game.settings.clone();
+            settings.host_side = None;
+            (lobby, settings)
+        };
+        let new_id = match self.quoridor_new_game_with_settings(&lobby, settings) {
+            Some(new_id) => new_id,
+            None => return PlayerMoveResult::Disallowed(MoveError::NotAvailable),
+        };
+        // both chats carry over, live and with their history
+        for (old_chat, new_chat) in [
+            (id.to_owned(), new_id.to_owned()),
+            (spectator_channel(id), spectator_channel(&new_id)),
+        ] {
+            let chat = self.chat_channel.read().unwrap().get(&old_chat).cloned();
+            if let Some(chat) = chat {
+                let history = self.chat_history.lock().unwrap();
+                history.set_access(&new_chat, &chat.access);
+                history.alias(&new_chat, &old_chat);
+                drop(history);
+                self.chat_channel.write().unwrap().insert(new_chat, chat);
+            }
+        }
+        let mut game = game.write().unwrap();
+        if game.rematch.is_some() {
+            drop(game);
+            self.quoridor_drop_by_id(&new_id);
+            return PlayerMoveResult::Disallowed(MoveError::NotAvailable);
+        }
+        let mark = game.mark();
+        game.rematch = Some(new_id);
+        let events = game.events_since(&mark);
+        channel.publish(&mut game, events);
+        self.quoridor_persist(id, &game);
+        PlayerMoveResult::Ok
+    }
+
+    pub fn tournament_create(&self, organizer: &str, payload: TournamentCreate) -> TournamentView {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let mut id = generate_id(ID_LEN);
+        while tournaments.contains_key(&id) {
+            id = generate_id(ID_LEN)
+        }
+        let tournament = Tournament::new(id.to_owned(), organizer, payload);
+        let view = TournamentView::from(&tournament);
+        tournaments.insert(id, (tournament, broadcast::channel::<TournamentView>(16).0));
+        view
+    }
+
+    pub fn tournament_get(&self, id: &str) -> Result<TournamentView, StateError> {
+        self.tournaments
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|(tournament, _)| tournament.into())
+            .ok_or(StateError::NotFound)
+    }
+
+    pub fn tournament_subscribe(&self, id: &str) -> Option<(TournamentView, broadcast::Receiver<TournamentView>)> {
+        self.tournaments
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|(tournament, sender)| (tournament.into(), sender.subscribe()))
+    }
+
+    pub fn tournament_register(&self, id: &str, player: &str) -> Result<TournamentView, StateError> {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let (tournament, sender) = tournaments.get_mut(id).ok_or(StateError::NotFound)?;
+        tournament.register(player)?;
+        let view = TournamentView::from(&*tournament);
+        let _ = sender.send(view.clone());
+        Ok(view)
+    }
+
+    pub fn tournament_start(&self, id: &str, player: &str) -> Result<TournamentView, StateError> {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let (tournament, sender) = tournaments.get_mut(id).ok_or(StateError::NotFound)?;
+        if tournament.organizer != player {
+            return Err(StateError::Unauthorized);
+        }
+        tournament.start()?;
+        self.tournament_start_matches(tournament);
+        let view = TournamentView::from(&*tournament);
+        let _ = sender.send(view.clone());
+        Ok(view)
+    }
+
+    fn tournament_start_matches(&self, tournament: &mut Tournament) {
+        if let Some(round) = tournament.current_round_mut() {
+            for pairing in round.iter_mut().filter(|pairing| pairing.match_id.is_none()) {
+                if let Some(down_player) = &pairing.down_player {
+                    pairing.match_id = self.quoridor_new_game(&[pairing.up_player.to_owned(), down_player.to_owned()]);
+                }
+            }
+        }
+    }
+
+    fn tournament_record_results(&self, finished: &[(String, MatchResult)]) {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        for (tournament, sender) in tournaments.values_mut() {
+            let mut changed = false;
+            for (match_id, result) in finished {
+                if tournament.has_match(match_id) {
+                    changed = true;
+                    if tournament.record_result(match_id, result) {
+                        self.tournament_start_matches(tournament);
+                    }
+                }
+            }
+            if changed {
+                let _ = sender.send(TournamentView::from(&*tournament));
+            }
+        }
+    }
+
+    /// Realtime game the player is in, correspondence games are listed by `quoridor_get_user_matches`.
     pub fn quoridor_get_id_by_player(&self, player: &str) -> Option<String> {
+        self.quoridor_find_live(player, true)
+    }
+
+    /// Live match shown to other users, private challenge games are left out.
+    pub fn quoridor_get_public_id_by_player(&self, player: &str) -> Option<String> {
+        self.quoridor_find_live(player, false)
+    }
+
+    fn quoridor_find_live(&self, player: &str, with_private: bool) -> Option<String> {
         let games = self.quoridor_games.lock().unwrap();
         games
             .iter()
-            .find(|(_key, (game, _))| game.read().unwrap().contains_player(player))
-            .map(|(key, _game_package)| (key.clone()))
+            .find(|(_key, (game, _))| {
+                let game = game.read().unwrap();
+                !game.is_finished()
+                    && !game.settings.is_correspondence()
+                    && (with_private || !game.settings.private)
+                    && game.contains_player(player)
+            })
+            .map(|(key, _game_package)| key.clone())
+    }
+
+    pub fn quoridor_is_private(&self, id: &str) -> bool {
+        self.quoridor_get_full(id)
+            .is_some_and(|(game, _)| game.read().unwrap().settings.private)
+    }
+
+    /// Private challenge games stay with their players, live as well as in replays.
+    pub fn quoridor_can_watch(&self, id: &str, viewer: &str) -> bool {
+        self.chat_access(id).can_read(viewer)
+    }
+
+    pub fn quoridor_get_user_matches(&self, player: &str) -> Vec<UserMatch> {
+        let games = self.quoridor_games.lock().unwrap();
+        let mut matches: Vec<UserMatch> = games
+            .iter()
+            .filter_map(|(key, (game, _))| {
+                let game = game.read().unwrap();
+                if game.is_finished() || !game.contains_player(player) {
+                    return None;
+                }
+                Some(UserMatch {
+                    id: key.to_owned(),
+                    opponent: game.opponent_of(player).to_owned(),
+                    your_turn: game.current_player() == player,
+                    turn: game.turns(),
+                    days_per_move: game.settings.days_per_move,
+                    deadline: game.deadline(),
+                })
+            })
+            .collect();
+        matches.sort_by_key(|user_match| (!user_match.your_turn, user_match.deadline));
+        matches
+    }
+
+    pub fn quoridor_fill_matches(&self, user: &mut UserContext) {
+        user.active_match = self.quoridor_get_id_by_player(&user.email);
+        user.active_matches = self
+            .quoridor_get_user_matches(&user.email)
+            .into_iter()
+            .map(|user_match| user_match.id)
+            .collect();
     }
 
     pub fn quoridor_get_full(&self, id: &str) -> Option<QuoridorPackage> {
         self.quoridor_games.lock().unwrap().get(id).cloned()
     }
 
+    /// Records the result for both players. It runs once, while the match is still locked,
+    /// so the update announcing the result already carries the unlocked achievements.
+    /// The finished match is archived right away so its replay survives a restart.
+    fn quoridor_settle(&self, id: &str, game: &mut QuoridorMatch) {
+        // guests and the CPU keep no records
+        let players: Vec<(String, String)> = {
+            let users = self.users.lock().unwrap();
+            [&game.up_player, &game.down_player]
+                .into_iter()
+                .filter_map(|player| Some((player.to_owned(), users.get_username(player)?)))
+                .collect()
+        };
+        let leaderboard = self.leaderboard.lock().unwrap();
+        for (player, _) in &players {
+            if let Some(outcome) = game.outcome_for(player) {
+                let opponent = game.opponent_of(player);
+                let summary = MatchSummary {
+                    opponent: self
+                        .users
+                        .lock()
+                        .unwrap()
+                        .get_username(opponent)
+                        .unwrap_or_else(|| opponent.to_owned()),
+                    outcome,
+                    turns: game.turns(),
+                    finished: chrono::Utc::now().timestamp(),
+                };
+                leaderboard.record_match(player, summary);
+            }
+        }
+        leaderboard.process_game(&players, game);
+        drop(leaderboard);
+        for (player, _) in &players {
+            let unlocked = self.achievements.lock().unwrap().process_game(player, game);
+            game.add_unlocked(player, unlocked);
+        }
+        self.archive.lock().unwrap().save(id, game);
+    }
+
+    /// Finished matches are replayable while they linger in memory and from the archive afterwards.
+    pub fn quoridor_replay(&self, id: &str, viewer: &str) -> Result<MatchReplay, StateError> {
+        let game = match self.quoridor_get_full(id) {
+            Some((game, _)) => Some(game.read().unwrap().clone()),
+            None => self.archive.lock().unwrap().get(id),
+        };
+        let game = game.filter(|game| game.is_finished()).ok_or(StateError::NotFound)?;
+        if !self.quoridor_can_watch(id, viewer) {
+            return Err(StateError::Unauthorized);
+        }
+        let chat = self.chat_history.lock().unwrap().latest(id, REPLAY_LEN);
+        Ok(MatchReplay {
+            id: id.to_owned(),
+            game,
+            chat,
+        })
+    }
+
     pub fn quoridor_drop_by_id(&self, id: &str) {
         self.quoridor_games.lock().unwrap().remove(id);
     }
 
     pub fn heart_beat(&self) {
         let mut chats_to_drop = Vec::new();
+        // only games that ended during this beat, lingering ones were announced already
+        let mut timed_out = Vec::new();
         let mut games = self.quoridor_games.lock().unwrap();
         println!("Active games: {}", games.len());
-        games.retain(|key, (game, sender)| {
+        games.retain(|key, (game, channel)| {
             let mut game = game.write().unwrap();
+            let was_running = !game.is_finished();
+            let mark = game.mark();
             game.timeout_guard();
-            let _ = sender.send(PlayerMoveResult::Ok);
-            if game.winner.is_some() {
+            if was_running && game.is_finished() {
+                self.quoridor_settle(key, &mut game);
+            }
+            let events = game.events_since(&mark);
+            channel.publish(&mut game, events);
+            if let Some(result) = game.result.clone().filter(|_| was_running) {
+                self.quoridor_persist(key, &game);
+                timed_out.push((key.to_owned(), result, game.settings.private));
+            }
+            if game.is_expired() {
+                self.archive.lock().unwrap().save(key, &game);
+                if game.settings.is_correspondence() {
+                    self.correspondence.lock().unwrap().remove(key);
+                }
                 chats_to_drop.push(key.to_owned());
+                chats_to_drop.push(spectator_channel(key));
                 false
             } else {
                 true
             }
         });
         drop(games);
+        for (id, result, private) in timed_out {
+            self.quoridor_finished(&id, result, private);
+        }
+        self.lobby_events.send(LobbyEvent::OnlinePlayers(self.online_count()));
+        self.challenges.lock().unwrap().clean_up();
+        self.chat_history.lock().unwrap().clean_up();
+        self.direct_relay.lock().unwrap().clean_up();
+        self.social.lock().unwrap().clean_up();
+        self.moderation.lock().unwrap().clean_up(chrono::Utc::now().timestamp());
         self.sessions
             .lock()
             .unwrap()
@@ -187,3 +1160,45 @@ pub fn generate_id(len: usize) -> String {
         .collect();
     s
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    #[test]
+    fn game_channel_replays_missed_events() {
+        let channel = GameChannel::default();
+        let mut game = QuoridorMatch::new(&["pl1".to_owned(), "pl2".to_owned()], MatchSettings::default());
+        for turn in 0..GAME_REPLAY_LEN + 2 {
+            let event = GameEvent::TurnChanged {
+                player: "pl1".to_owned(),
+                turn,
+            };
+            channel.publish(&mut game, vec![event]);
+        }
+        let current = game.seq;
+        let missed = channel.missed(current - 3, current).unwrap();
+        assert_eq!(
+            missed.iter().map(|event| event.seq).collect::<Vec<_>>(),
+            vec![current - 2, current - 1, current]
+        );
+        assert!(channel.missed(current, current).unwrap().is_empty());
+        assert!(channel.missed(1, current).is_none());
+        assert!(channel.missed(current + 1, current).is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_game_subscriber_gets_one_snapshot() {
+        let (sender, mut receiver) = broadcast::channel::<Vec<SequencedEvent>>(1);
+        for _ in 0..3 {
+            sender.send(Vec::new()).unwrap();
+        }
+        assert!(matches!(game_update(&mut receiver).await, Some(GameUpdate::Lagged)));
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+        sender.send(Vec::new()).unwrap();
+        assert!(matches!(game_update(&mut receiver).await, Some(GameUpdate::Events(_))));
+        drop(sender);
+        assert!(game_update(&mut receiver).await.is_none());
+    }
+}
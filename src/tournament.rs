@@ -0,0 +1,481 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::StateError;
+use crate::quoridor::MatchResult;
+
+const WIN_POINTS: f32 = 1.0;
+const DRAW_POINTS: f32 = 0.5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TournamentFormat {
+    RoundRobin,
+    Swiss { rounds: usize },
+    SingleElimination,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TournamentStatus {
+    Registration,
+    Running,
+    Finished,
+}
+
+#[derive(Deserialize)]
+pub struct TournamentCreate {
+    pub name: String,
+    pub format: TournamentFormat,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Pairing {
+    pub up_player: String,
+    /// None is a bye, the up player gets the point without playing.
+    pub down_player: Option<String>,
+    pub match_id: Option<String>,
+    pub winner: Option<String>,
+    pub draw: bool,
+}
+
+impl Pairing {
+    fn new(up_player: &str, down_player: Option<&str>) -> Self {
+        Self {
+            up_player: up_player.to_owned(),
+            down_player: down_player.map(|player| player.to_owned()),
+            match_id: None,
+            winner: if down_player.is_none() {
+                Some(up_player.to_owned())
+            } else {
+                None
+            },
+            draw: false,
+        }
+    }
+
+    fn is_decided(&self) -> bool {
+        self.winner.is_some() || self.draw
+    }
+
+    /// Knockout draws send the higher seed through, seeds are always paired as the up player.
+    fn advancing(&self) -> Option<&str> {
+        match &self.winner {
+            Some(winner) => Some(winner),
+            None if self.draw => Some(&self.up_player),
+            None => None,
+        }
+    }
+
+    fn contains_player(&self, player: &str) -> bool {
+        self.up_player == player || self.down_player.as_deref() == Some(player)
+    }
+
+    fn opponent_of(&self, player: &str) -> Option<&str> {
+        if self.up_player == player {
+            self.down_player.as_deref()
+        } else if self.down_player.as_deref() == Some(player) {
+            Some(&self.up_player)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Standing {
+    pub player: String,
+    pub points: f32,
+    pub buchholz: f32,
+    pub wins: usize,
+    pub draws: usize,
+    pub loses: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Tournament {
+    pub id: String,
+    pub name: String,
+    pub organizer: String,
+    pub format: TournamentFormat,
+    pub status: TournamentStatus,
+    pub players: Vec<String>,
+    pub rounds: Vec<Vec<Pairing>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentView {
+    pub tournament: Tournament,
+    pub standings: Vec<Standing>,
+}
+
+impl From<&Tournament> for TournamentView {
+    fn from(value: &Tournament) -> Self {
+        Self {
+            standings: value.standings(),
+            tournament: value.clone(),
+        }
+    }
+}
+
+impl Tournament {
+    pub fn new(id: String, organizer: &str, payload: TournamentCreate) -> Self {
+        Self {
+            id,
+            name: payload.name,
+            organizer: organizer.to_owned(),
+            format: payload.format,
+            status: TournamentStatus::Registration,
+            players: Vec::new(),
+            rounds: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, player: &str) -> Result<(), StateError> {
+        if self.status != TournamentStatus::Registration {
+            return Err(StateError::UnsupportedDataType("Registration is closed".into()));
+        }
+        if self.players.iter().any(|registered| registered == player) {
+            return Err(StateError::AlreadyTaken);
+        }
+        self.players.push(player.to_owned());
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), StateError> {
+        if self.status != TournamentStatus::Registration {
+            return Err(StateError::AlreadyTaken);
+        }
+        if self.players.len() < 2 {
+            return Err(StateError::UnsupportedDataType("Not enough players".into()));
+        }
+        self.status = TournamentStatus::Running;
+        self.advance();
+        Ok(())
+    }
+
+    pub fn current_round_mut(&mut self) -> Option<&mut Vec<Pairing>> {
+        self.rounds.last_mut()
+    }
+
+    pub fn has_match(&self, match_id: &str) -> bool {
+        self.rounds.last().is_some_and(|round| {
+            round
+                .iter()
+                .any(|pairing| pairing.match_id.as_deref() == Some(match_id))
+        })
+    }
+
+    /// Stores the result of a finished match and moves on to the next round once every pairing is decided.
+    /// Returns true when a new round was generated.
+    pub fn record_result(&mut self, match_id: &str, result: &MatchResult) -> bool {
+        let round = match self.rounds.last_mut() {
+            Some(round) => round,
+            None => return false,
+        };
+        let pairing = round
+            .iter_mut()
+            .find(|pairing| pairing.match_id.as_deref() == Some(match_id) && !pairing.is_decided());
+        if let Some(pairing) = pairing {
+            match result {
+                MatchResult::Win { winner, .. } if pairing.contains_player(winner) => {
+                    pairing.winner = Some(winner.to_owned())
+                }
+                MatchResult::Win { .. } => (),
+                MatchResult::Draw { .. } => pairing.draw = true,
+            }
+        }
+        if round.iter().all(|pairing| pairing.is_decided()) {
+            return self.advance();
+        }
+        false
+    }
+
+    fn advance(&mut self) -> bool {
+        match self.next_round() {
+            Some(round) => {
+                self.rounds.push(round);
+                true
+            }
+            None => {
+                self.status = TournamentStatus::Finished;
+                false
+            }
+        }
+    }
+
+    fn next_round(&self) -> Option<Vec<Pairing>> {
+        match self.format {
+            TournamentFormat::RoundRobin => self.round_robin_pairings(),
+            TournamentFormat::Swiss { rounds } => self.swiss_pairings(rounds),
+            TournamentFormat::SingleElimination => self.elimination_pairings(),
+        }
+    }
+
+    /// Circle method, the first player stays in place while the rest rotate every round.
+    fn round_robin_pairings(&self) -> Option<Vec<Pairing>> {
+        let mut seats: Vec<Option<&str>> = self.players.iter().map(|player| Some(player.as_str())).collect();
+        if seats.len() % 2 == 1 {
+            seats.push(None);
+        }
+        let round = self.rounds.len();
+        if round >= seats.len() - 1 {
+            return None;
+        }
+        seats[1..].rotate_right(round);
+        let half = seats.len() / 2;
+        let pairings = (0..half)
+            .filter_map(|idx| {
+                let (mut up, mut down) = (seats[idx], seats[seats.len() - 1 - idx]);
+                if idx == 0 && round % 2 == 1 {
+                    std::mem::swap(&mut up, &mut down);
+                }
+                match (up, down) {
+                    (Some(up), down) => Some(Pairing::new(up, down)),
+                    (None, Some(down)) => Some(Pairing::new(down, None)),
+                    (None, None) => None,
+                }
+            })
+            .collect();
+        Some(pairings)
+    }
+
+    /// Players are ranked by points and Buchholz, each takes the best ranked opponent they have not met yet.
+    fn swiss_pairings(&self, rounds: usize) -> Option<Vec<Pairing>> {
+        if self.rounds.len() >= rounds.min(self.players.len() - 1) {
+            return None;
+        }
+        let mut ranked: Vec<String> = self.standings().into_iter().map(|standing| standing.player).collect();
+        let mut pairings = Vec::new();
+        if ranked.len() % 2 == 1 {
+            let bye_idx = ranked
+                .iter()
+                .rposition(|player| !self.had_bye(player))
+                .unwrap_or(ranked.len() - 1);
+            pairings.push(Pairing::new(&ranked.remove(bye_idx), None));
+        }
+        let matched = self.pair_unmet(&ranked).unwrap_or_else(|| {
+            ranked
+                .chunks(2)
+                .map(|pair| (pair[0].to_owned(), pair[1].to_owned()))
+                .collect()
+        });
+        for (player, opponent) in matched {
+            pairings.push(Pairing::new(&player, Some(&opponent)));
+        }
+        Some(pairings)
+    }
+
+    /// Backtracking search for pairings without rematches, keeping the ranking order as much as possible.
+    fn pair_unmet(&self, ranked: &[String]) -> Option<Vec<(String, String)>> {
+        let (player, rest) = match ranked.split_first() {
+            Some(split) => split,
+            None => return Some(Vec::new()),
+        };
+        for (idx, opponent) in rest.iter().enumerate() {
+            if self.have_met(player, opponent) {
+                continue;
+            }
+            let mut remaining = rest.to_vec();
+            remaining.remove(idx);
+            if let Some(mut matched) = self.pair_unmet(&remaining) {
+                matched.insert(0, (player.to_owned(), opponent.to_owned()));
+                return Some(matched);
+            }
+        }
+        None
+    }
+
+    /// Seeds follow registration order, the top seed takes the bye when the field is odd.
+    fn elimination_pairings(&self) -> Option<Vec<Pairing>> {
+        let mut alive: Vec<&str> = match self.rounds.last() {
+            Some(round) => round.iter().filter_map(|pairing| pairing.advancing()).collect(),
+            None => self.players.iter().map(|player| player.as_str()).collect(),
+        };
+        if alive.len() < 2 {
+            return None;
+        }
+        let mut pairings = Vec::new();
+        if alive.len() % 2 == 1 {
+            pairings.push(Pairing::new(alive.remove(0), None));
+        }
+        let half = alive.len() / 2;
+        for idx in 0..half {
+            pairings.push(Pairing::new(alive[idx], Some(alive[alive.len() - 1 - idx])));
+        }
+        Some(pairings)
+    }
+
+    fn had_bye(&self, player: &str) -> bool {
+        self.pairings()
+            .any(|pairing| pairing.up_player == player && pairing.down_player.is_none())
+    }
+
+    fn have_met(&self, player: &str, opponent: &str) -> bool {
+        self.pairings()
+            .any(|pairing| pairing.contains_player(player) && pairing.contains_player(opponent))
+    }
+
+    fn pairings(&self) -> impl Iterator<Item = &Pairing> {
+        self.rounds.iter().flatten()
+    }
+
+    fn points(&self, player: &str) -> f32 {
+        self.pairings()
+            .filter(|pairing| pairing.contains_player(player))
+            .map(|pairing| match &pairing.winner {
+                Some(winner) if winner == player => WIN_POINTS,
+                None if pairing.draw => DRAW_POINTS,
+                _ => 0.0,
+            })
+            .sum()
+    }
+
+    pub fn standings(&self) -> Vec<Standing> {
+        let mut standings: Vec<Standing> = self
+            .players
+            .iter()
+            .map(|player| {
+                let played: Vec<&Pairing> = self
+                    .pairings()
+                    .filter(|pairing| pairing.contains_player(player) && pairing.is_decided())
+                    .collect();
+                let wins = played
+                    .iter()
+                    .filter(|pairing| pairing.winner.as_deref() == Some(player.as_str()))
+                    .count();
+                let draws = played.iter().filter(|pairing| pairing.draw).count();
+                Standing {
+                    player: player.to_owned(),
+                    points: self.points(player),
+                    buchholz: played
+                        .iter()
+                        .filter_map(|pairing| pairing.opponent_of(player))
+                        .map(|opponent| self.points(opponent))
+                        .sum(),
+                    wins,
+                    draws,
+                    loses: played.len() - wins - draws,
+                }
+            })
+            .collect();
+        standings.sort_by(|a, b| {
+            b.points
+                .partial_cmp(&a.points)
+                .unwrap_or(Ordering::Equal)
+                .then(b.buchholz.partial_cmp(&a.buchholz).unwrap_or(Ordering::Equal))
+        });
+        standings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quoridor::{DrawReason, WinReason};
+
+    fn tournament(format: TournamentFormat, players: usize) -> Tournament {
+        let payload = TournamentCreate {
+            name: "test".to_owned(),
+            format,
+        };
+        let mut tournament = Tournament::new("id".to_owned(), "org", payload);
+        for idx in 0..players {
+            tournament.register(&format!("pl{idx}")).unwrap();
+        }
+        tournament.start().unwrap();
+        tournament
+    }
+
+    /// Plays out the current round, the up player always wins.
+    fn play_round(tournament: &mut Tournament) {
+        let results: Vec<(String, MatchResult)> = tournament
+            .current_round_mut()
+            .unwrap()
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, pairing)| pairing.winner.is_none())
+            .map(|(idx, pairing)| {
+                let match_id = format!("m{idx}");
+                pairing.match_id = Some(match_id.to_owned());
+                let result = MatchResult::Win {
+                    winner: pairing.up_player.to_owned(),
+                    reason: WinReason::ReachedGoal,
+                };
+                (match_id, result)
+            })
+            .collect();
+        for (match_id, result) in results {
+            tournament.record_result(&match_id, &result);
+        }
+    }
+
+    #[test]
+    fn round_robin_everyone_meets_once() {
+        let mut tournament = tournament(TournamentFormat::RoundRobin, 5);
+        while tournament.status == TournamentStatus::Running {
+            play_round(&mut tournament);
+        }
+        assert_eq!(tournament.rounds.len(), 5);
+        for player in &tournament.players {
+            for opponent in tournament.players.iter().filter(|opponent| *opponent != player) {
+                let meetings = tournament
+                    .pairings()
+                    .filter(|pairing| pairing.contains_player(player) && pairing.contains_player(opponent))
+                    .count();
+                assert_eq!(meetings, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn swiss_avoids_rematches() {
+        let mut tournament = tournament(TournamentFormat::Swiss { rounds: 3 }, 6);
+        while tournament.status == TournamentStatus::Running {
+            play_round(&mut tournament);
+        }
+        assert_eq!(tournament.rounds.len(), 3);
+        for round in &tournament.rounds[1..] {
+            for pairing in round {
+                let down = pairing.down_player.as_deref().unwrap();
+                let meetings = tournament
+                    .pairings()
+                    .filter(|other| other.contains_player(&pairing.up_player) && other.contains_player(down))
+                    .count();
+                assert_eq!(meetings, 1);
+            }
+        }
+        let standings = tournament.standings();
+        assert!(standings.windows(2).all(|pair| pair[0].points >= pair[1].points));
+    }
+
+    #[test]
+    fn elimination_crowns_single_winner() {
+        let mut tournament = tournament(TournamentFormat::SingleElimination, 5);
+        assert!(tournament.rounds[0].iter().any(|pairing| pairing.down_player.is_none()));
+        while tournament.status == TournamentStatus::Running {
+            play_round(&mut tournament);
+        }
+        let last_round = tournament.rounds.last().unwrap();
+        assert_eq!(last_round.len(), 1);
+        assert!(last_round[0].winner.is_some());
+    }
+
+    #[test]
+    fn elimination_draw_advances_higher_seed() {
+        let mut tournament = tournament(TournamentFormat::SingleElimination, 2);
+        tournament.current_round_mut().unwrap()[0].match_id = Some("m0".to_owned());
+        let draw = MatchResult::Draw {
+            reason: DrawReason::Agreement,
+        };
+        tournament.record_result("m0", &draw);
+        assert_eq!(tournament.status, TournamentStatus::Finished);
+        assert_eq!(tournament.rounds[0][0].advancing(), Some("pl0"));
+        assert!(tournament
+            .standings()
+            .iter()
+            .all(|standing| standing.points == DRAW_POINTS));
+    }
+}